@@ -0,0 +1,244 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+use crate::rdb::unix_millis;
+use crate::resp::{self, RespValue};
+use crate::storage::{select, Storage, Value};
+use crate::stream::StreamId;
+
+/// When the append-only file is flushed to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fsync {
+    /// After every appended command.
+    Always,
+    /// Once per second, from a background task.
+    EverySec,
+}
+
+impl Fsync {
+    pub fn parse(value: &str) -> Option<Fsync> {
+        match value.to_ascii_lowercase().as_str() {
+            "always" => Some(Fsync::Always),
+            "everysec" => Some(Fsync::EverySec),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Fsync::Always => "always",
+            Fsync::EverySec => "everysec",
+        }
+    }
+}
+
+pub struct Aof {
+    file: File,
+    /// Whether commands were appended since the last sync.
+    dirty: bool,
+    /// Database the appended commands apply to, once a SELECT was written.
+    selected_db: Option<usize>,
+}
+
+impl Aof {
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Aof {
+            file,
+            dirty: false,
+            selected_db: None,
+        })
+    }
+
+    /// Appends a command that ran against database `db`, preceded by a
+    /// SELECT if the previous one ran against another database.
+    pub fn append(&mut self, db: usize, command: &RespValue, fsync: Fsync) -> std::io::Result<()> {
+        if self.selected_db != Some(db) {
+            self.file.write_all(&select(db).to_bytes())?;
+            self.selected_db = Some(db);
+        }
+        let absolute = absolute_expiry(command, unix_millis());
+        self.file
+            .write_all(&absolute.as_ref().unwrap_or(command).to_bytes())?;
+        self.dirty = true;
+        if fsync == Fsync::Always {
+            self.sync()?;
+        }
+        Ok(())
+    }
+
+    pub fn sync(&mut self) -> std::io::Result<()> {
+        if self.dirty {
+            self.file.sync_data()?;
+            self.dirty = false;
+        }
+        Ok(())
+    }
+}
+
+/// Writes an append-only file at `path` recreating every live key of
+/// `databases`, each given as its shards. Like RDB saves, the file is written
+/// next to `path` first and then renamed over it.
+pub fn rewrite(path: &Path, databases: &[Vec<&Storage>]) -> std::io::Result<()> {
+    let now = unix_millis();
+    let mut out = vec![];
+    for (db, shards) in databases.iter().enumerate() {
+        let mut selected = false;
+        for (key, data) in shards.iter().flat_map(|shard| shard.data.iter()) {
+            if data.is_expired() {
+                continue;
+            }
+            if !selected {
+                out.extend_from_slice(&select(db).to_bytes());
+                selected = true;
+            }
+            for command in recreate(key, &data.value) {
+                out.extend_from_slice(&command.to_bytes());
+            }
+            if let Some(ttl) = data.ttl() {
+                let at = now + ttl.as_millis() as u64;
+                let command = array(vec![
+                    b"PEXPIREAT".to_vec(),
+                    key.as_bytes().to_vec(),
+                    at.to_string().into_bytes(),
+                ]);
+                out.extend_from_slice(&command.to_bytes());
+            }
+        }
+    }
+
+    let tmp_path = path.with_extension(format!("tmp-{}", std::process::id()));
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(&out)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp_path, path)
+}
+
+/// The commands creating `key` with `value`.
+fn recreate(key: &str, value: &Value) -> Vec<RespValue> {
+    let key = key.as_bytes().to_vec();
+    let command = |name: &str, elements: Vec<Vec<u8>>| {
+        array([vec![name.as_bytes().to_vec(), key.clone()], elements].concat())
+    };
+    match value {
+        Value::String(value) => vec![command("SET", vec![value.to_vec()])],
+        Value::List(items) => vec![command("RPUSH", items.iter().cloned().collect())],
+        Value::Set(members) => vec![command("SADD", members.iter().cloned().collect())],
+        Value::Hash(fields) => vec![command(
+            "HSET",
+            fields
+                .iter()
+                .flat_map(|(field, value)| [field.clone(), value.clone()])
+                .collect(),
+        )],
+        Value::SortedSet(zset) => vec![command(
+            "ZADD",
+            zset.iter()
+                .flat_map(|(member, score)| [score.to_string().into_bytes(), member.to_vec()])
+                .collect(),
+        )],
+        Value::Stream(stream) => stream
+            .range(StreamId::default(), StreamId::MAX)
+            .map(|(id, fields)| {
+                let mut elements = vec![id.to_string().into_bytes()];
+                for (field, value) in fields {
+                    elements.extend([field.clone(), value.clone()]);
+                }
+                command("XADD", elements)
+            })
+            .collect(),
+    }
+}
+
+fn array(elements: Vec<Vec<u8>>) -> RespValue {
+    RespValue::Array(
+        elements
+            .into_iter()
+            .map(|element| RespValue::BulkString(Some(element)))
+            .collect(),
+    )
+}
+
+/// Rewrites a command setting a relative expiry into one setting the absolute
+/// unix time in milliseconds it amounted to at `now`. Replayed as is, it would
+/// extend the key's lifetime by however long the server was down.
+fn absolute_expiry(command: &RespValue, now: u64) -> Option<RespValue> {
+    let RespValue::Array(parts) = command else {
+        return None;
+    };
+    let word = |i: usize| match parts.get(i) {
+        Some(RespValue::BulkString(Some(word))) => {
+            Some(String::from_utf8_lossy(word).to_ascii_lowercase())
+        }
+        _ => None,
+    };
+    let at = |i: usize, scale: i64| {
+        let amount = word(i)?.parse::<i64>().ok()?;
+        let at = amount.saturating_mul(scale).saturating_add(now as i64);
+        Some(bulk(&at.max(0).to_string()))
+    };
+
+    let key = parts.get(1)?.clone();
+    match word(0)?.as_str() {
+        "expire" => Some(RespValue::Array(vec![bulk("PEXPIREAT"), key, at(2, 1000)?])),
+        "pexpire" => Some(RespValue::Array(vec![bulk("PEXPIREAT"), key, at(2, 1)?])),
+        command @ ("setex" | "psetex") => {
+            let scale = if command == "setex" { 1000 } else { 1 };
+            let value = parts.get(3)?.clone();
+            Some(RespValue::Array(vec![
+                bulk("SET"),
+                key,
+                value,
+                bulk("PXAT"),
+                at(2, scale)?,
+            ]))
+        }
+        "set" => {
+            // Past the value, EX and PX are the only options taking an
+            // argument, so any other word is an option of its own.
+            let mut rewritten = parts.clone();
+            let mut changed = false;
+            let mut i = 3;
+            while i < parts.len() {
+                let scale = match word(i).as_deref() {
+                    Some("ex") => 1000,
+                    Some("px") => 1,
+                    _ => {
+                        i += 1;
+                        continue;
+                    }
+                };
+                rewritten[i] = bulk("PXAT");
+                rewritten[i + 1] = at(i + 1, scale)?;
+                changed = true;
+                i += 2;
+            }
+            changed.then_some(RespValue::Array(rewritten))
+        }
+        _ => None,
+    }
+}
+
+fn bulk(word: &str) -> RespValue {
+    RespValue::BulkString(Some(word.as_bytes().to_vec()))
+}
+
+/// Reads back every command stored in the append-only file at `path`. A
+/// command cut off at the end, as left by a crash mid-write, is dropped from
+/// the file so that later appends do not follow it.
+pub fn load(path: &Path) -> Result<Vec<RespValue>, anyhow::Error> {
+    let bytes = std::fs::read(path)?;
+    let (commands, len) = resp::parse_all(&bytes)?;
+    if len < bytes.len() {
+        eprintln!(
+            "append-only file ends with a truncated command, dropping its last {} bytes",
+            bytes.len() - len
+        );
+        OpenOptions::new()
+            .write(true)
+            .open(path)?
+            .set_len(len as u64)?;
+    }
+    Ok(commands)
+}
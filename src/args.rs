@@ -0,0 +1,185 @@
+use anyhow::anyhow;
+
+use crate::aof::Fsync;
+use crate::eviction::Policy;
+
+pub const USAGE: &str =
+    "usage: redis-starter-rust [--port <port>] [--replicaof \"<host> <port>\"] [--dir <dir>] [--dbfilename <file>] [--appendonly yes|no] [--appendfilename <file>] [--appendfsync always|everysec] [--databases <count>] [--maxmemory <bytes>] [--maxmemory-policy noeviction|allkeys-lru]";
+
+/// Command-line options the server was started with.
+pub struct Args {
+    pub port: u16,
+    pub replicaof: Option<(String, u16)>,
+    pub dir: String,
+    pub dbfilename: String,
+    pub appendonly: bool,
+    pub appendfilename: String,
+    pub appendfsync: Fsync,
+    pub databases: usize,
+    pub maxmemory: u64,
+    pub maxmemory_policy: Policy,
+}
+
+/// Parses the arguments following the program name.
+pub fn parse_args(args: &[String]) -> Result<Args, anyhow::Error> {
+    let mut parsed = Args {
+        port: 6379,
+        replicaof: None,
+        dir: ".".to_string(),
+        dbfilename: "dump.rdb".to_string(),
+        appendonly: false,
+        appendfilename: "appendonly.aof".to_string(),
+        appendfsync: Fsync::EverySec,
+        databases: 16,
+        maxmemory: 0,
+        maxmemory_policy: Policy::NoEviction,
+    };
+
+    let mut args = args.iter().peekable();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .cloned()
+                .ok_or_else(|| anyhow!("missing value for {}", arg))
+        };
+
+        match arg.as_str() {
+            "--port" => {
+                let port = value()?;
+                parsed.port = port
+                    .parse::<u16>()
+                    .map_err(|_| anyhow!("invalid port '{}'", port))?;
+            }
+            "--replicaof" => {
+                let replicaof = value()?;
+                // Accept both `--replicaof "host port"` and `--replicaof host port`.
+                let (host, port) = match replicaof.split_once(' ') {
+                    Some((host, port)) => (host.to_string(), port.trim().to_string()),
+                    None => (
+                        replicaof,
+                        args.next_if(|next| !next.starts_with("--"))
+                            .cloned()
+                            .ok_or_else(|| anyhow!("missing master port for --replicaof"))?,
+                    ),
+                };
+                let port = port
+                    .parse::<u16>()
+                    .map_err(|_| anyhow!("invalid master port '{}'", port))?;
+                parsed.replicaof = Some((host, port));
+            }
+            "--dir" => parsed.dir = value()?,
+            "--dbfilename" => parsed.dbfilename = value()?,
+            "--appendonly" => {
+                parsed.appendonly = match value()?.to_ascii_lowercase().as_str() {
+                    "yes" => true,
+                    "no" => false,
+                    other => return Err(anyhow!("invalid value '{}' for --appendonly", other)),
+                }
+            }
+            "--appendfilename" => parsed.appendfilename = value()?,
+            "--appendfsync" => {
+                let fsync = value()?;
+                parsed.appendfsync = Fsync::parse(&fsync)
+                    .ok_or_else(|| anyhow!("invalid value '{}' for --appendfsync", fsync))?;
+            }
+            "--databases" => {
+                let databases = value()?;
+                parsed.databases = databases
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|&databases| databases > 0)
+                    .ok_or_else(|| anyhow!("invalid number of databases '{}'", databases))?;
+            }
+            "--maxmemory" => {
+                let maxmemory = value()?;
+                parsed.maxmemory = parse_memory(&maxmemory)
+                    .ok_or_else(|| anyhow!("invalid memory amount '{}'", maxmemory))?;
+            }
+            "--maxmemory-policy" => {
+                let policy = value()?;
+                parsed.maxmemory_policy = Policy::parse(&policy)
+                    .ok_or_else(|| anyhow!("invalid value '{}' for --maxmemory-policy", policy))?;
+            }
+            _ => return Err(anyhow!("unknown option '{}'", arg)),
+        }
+    }
+
+    Ok(parsed)
+}
+
+/// Parses a memory amount such as `100`, `512kb` or `1gb` into bytes.
+pub fn parse_memory(value: &str) -> Option<u64> {
+    let value = value.to_ascii_lowercase();
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => value.split_at(i),
+        None => (value.as_str(), ""),
+    };
+    let multiplier = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, anyhow::Error> {
+        parse_args(&args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn malformed_arguments_are_errors() {
+        for args in [
+            &["--port"][..],
+            &["--port", "http"],
+            &["--port", "70000"],
+            &["--replicaof"],
+            &["--replicaof", "localhost"],
+            &["--replicaof", "localhost", "--port", "6380"],
+            &["--replicaof", "localhost port"],
+            &["--databases", "0"],
+            &["--maxmemory", "lots"],
+            &["--appendonly", "maybe"],
+            &["--verbose"],
+        ] {
+            assert!(parse(args).is_err(), "{:?} should not parse", args);
+        }
+    }
+
+    #[test]
+    fn replicaof_takes_one_or_two_tokens() {
+        for args in [
+            &["--replicaof", "localhost 6380"][..],
+            &["--replicaof", "localhost", "6380"],
+        ] {
+            let parsed = parse(args).unwrap();
+            assert_eq!(parsed.replicaof, Some(("localhost".to_string(), 6380)));
+        }
+    }
+
+    #[test]
+    fn defaults_apply_to_missing_options() {
+        let parsed = parse(&["--port", "6380"]).unwrap();
+        assert_eq!(parsed.port, 6380);
+        assert_eq!(parsed.replicaof, None);
+        assert_eq!(parsed.databases, 16);
+    }
+
+    #[test]
+    fn memory_amounts_accept_units() {
+        assert_eq!(parse_memory("100"), Some(100));
+        assert_eq!(parse_memory("1kb"), Some(1024));
+        assert_eq!(parse_memory("2MB"), Some(2 * 1024 * 1024));
+        assert_eq!(parse_memory("1k"), Some(1000));
+        assert_eq!(parse_memory("1tb"), None);
+        assert_eq!(parse_memory("-1"), None);
+    }
+}
@@ -0,0 +1,87 @@
+/// Strings are limited to 512MB, which bounds the bit offsets SETBIT accepts.
+pub const MAX_OFFSET: i64 = 1 << 32;
+
+/// The bit at `offset`, counting from the most significant bit of the first
+/// byte. Bits past the end of the string are zero.
+pub fn get_bit(bits: &[u8], offset: usize) -> bool {
+    match bits.get(offset / 8) {
+        Some(byte) => byte & (0x80 >> (offset % 8)) != 0,
+        None => false,
+    }
+}
+
+/// Sets the bit at `offset` to `value`, growing the string with zero bytes
+/// as needed, and returns its previous value.
+pub fn set_bit(bits: &mut Vec<u8>, offset: usize, value: bool) -> bool {
+    let index = offset / 8;
+    if bits.len() <= index {
+        bits.resize(index + 1, 0);
+    }
+
+    let mask = 0x80 >> (offset % 8);
+    let previous = bits[index] & mask != 0;
+    match value {
+        true => bits[index] |= mask,
+        false => bits[index] &= !mask,
+    }
+    previous
+}
+
+/// Number of set bits between bit offsets `start` and `end`, inclusive,
+/// which must both be within `bits`.
+pub fn count(bits: &[u8], start: usize, end: usize) -> usize {
+    let (first, last) = (start / 8, end / 8);
+    let whole = popcount(&bits[first..=last]);
+    // Leave out the bits of the first and last bytes outside the range.
+    let before = bits[first] & !(0xFF >> (start % 8));
+    let after = bits[last] & (0xFFu16 >> (end % 8 + 1)) as u8;
+    whole - before.count_ones() as usize - after.count_ones() as usize
+}
+
+pub fn popcount(bytes: &[u8]) -> usize {
+    bytes.iter().map(|byte| byte.count_ones() as usize).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn popcount_counts_every_set_bit() {
+        assert_eq!(popcount(b""), 0);
+        assert_eq!(popcount(b"foobar"), 26);
+        assert_eq!(popcount(&[0xFF, 0x01]), 9);
+    }
+
+    #[test]
+    fn count_covers_byte_and_bit_ranges() {
+        // BITCOUNT mykey 1 1 counts the bits of the second byte.
+        assert_eq!(count(b"foobar", 8, 15), 6);
+        // BITCOUNT mykey 5 30 BIT.
+        assert_eq!(count(b"foobar", 5, 30), 17);
+        assert_eq!(count(b"foobar", 0, 47), 26);
+        assert_eq!(count(&[0x80], 0, 0), 1);
+        assert_eq!(count(&[0x80], 1, 7), 0);
+    }
+
+    #[test]
+    fn count_agrees_with_get_bit() {
+        let bits = b"\xA5\x0Fz";
+        for start in 0..24 {
+            for end in start..24 {
+                let expected = (start..=end).filter(|&bit| get_bit(bits, bit)).count();
+                assert_eq!(count(bits, start, end), expected, "{}..={}", start, end);
+            }
+        }
+    }
+
+    #[test]
+    fn set_bit_grows_the_string() {
+        let mut bits = vec![];
+        assert!(!set_bit(&mut bits, 10, true));
+        assert_eq!(bits, [0x00, 0x20]);
+        assert!(set_bit(&mut bits, 10, false));
+        assert!(!get_bit(&bits, 10));
+        assert!(!get_bit(&bits, 100));
+    }
+}
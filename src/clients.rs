@@ -0,0 +1,66 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+/// A connected client, as reported by CLIENT LIST.
+pub struct Client {
+    pub addr: String,
+    pub name: Option<String>,
+}
+
+/// Every connected client, keyed by its connection id.
+pub struct Clients {
+    next_id: u64,
+    clients: BTreeMap<u64, Client>,
+}
+
+impl Clients {
+    pub fn new() -> Self {
+        Clients {
+            next_id: 1,
+            clients: BTreeMap::new(),
+        }
+    }
+
+    /// Registers a new connection from `addr`. The client stays listed until
+    /// the returned guard is dropped.
+    pub fn register(clients: &Arc<Mutex<Clients>>, addr: String) -> Registration {
+        let mut locked = clients.lock().unwrap();
+        let id = locked.next_id;
+        locked.next_id += 1;
+        locked.clients.insert(id, Client { addr, name: None });
+
+        Registration {
+            clients: clients.clone(),
+            id,
+        }
+    }
+
+    pub fn name(&self, id: u64) -> Option<String> {
+        self.clients.get(&id).and_then(|client| client.name.clone())
+    }
+
+    pub fn set_name(&mut self, id: u64, name: Option<String>) {
+        if let Some(client) = self.clients.get_mut(&id) {
+            client.name = name;
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&u64, &Client)> {
+        self.clients.iter()
+    }
+}
+
+/// Removes a client from the registry when its connection ends, however it
+/// ends.
+pub struct Registration {
+    clients: Arc<Mutex<Clients>>,
+    pub id: u64,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        if let Ok(mut clients) = self.clients.lock() {
+            clients.clients.remove(&self.id);
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use std::time::Duration;
+
+use crate::storage::Databases;
+
+/// What happens once the keyspace outgrows `maxmemory`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// Commands that would use more memory are refused.
+    NoEviction,
+    /// The least recently accessed keys are evicted to make room.
+    AllKeysLru,
+}
+
+impl Policy {
+    pub fn parse(value: &str) -> Option<Policy> {
+        match value.to_ascii_lowercase().as_str() {
+            "noeviction" => Some(Policy::NoEviction),
+            "allkeys-lru" => Some(Policy::AllKeysLru),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Policy::NoEviction => "noeviction",
+            Policy::AllKeysLru => "allkeys-lru",
+        }
+    }
+}
+
+pub const OOM: &str = "OOM command not allowed when used memory > 'maxmemory'";
+
+/// Whether `command` may use more memory, and is refused when the keyspace
+/// cannot be brought back under `maxmemory`. Commands that only remove data
+/// always run, since they are the way out.
+pub fn may_grow(command: &str) -> bool {
+    crate::replication::is_write_command(command)
+        && !matches!(
+            command,
+            "del"
+                | "unlink"
+                | "getdel"
+                | "expire"
+                | "pexpire"
+                | "expireat"
+                | "pexpireat"
+                | "persist"
+                | "rename"
+                | "renamenx"
+                | "move"
+                | "swapdb"
+                | "lpop"
+                | "rpop"
+                | "hdel"
+                | "srem"
+                | "flushdb"
+                | "flushall"
+        )
+}
+
+/// Keys sampled from each database for every key evicted, as Redis does
+/// with `maxmemory-samples`.
+const SAMPLES: usize = 5;
+/// Candidates kept between samples, the most idle of which is evicted next.
+const POOL_SIZE: usize = 16;
+
+/// Brings the keyspace back under `maxmemory` bytes if it went over, when
+/// `policy` allows evicting. Like Redis, this approximates LRU by sampling a
+/// few keys at a time and evicting the most idle of the best candidates seen
+/// so far. Returns the `(db, key)` pairs evicted, or the error to refuse
+/// commands with under `noeviction`.
+pub async fn evict(
+    databases: &Databases,
+    maxmemory: u64,
+    policy: Policy,
+) -> Result<Vec<(usize, String)>, &'static str> {
+    let over = || databases.memory().used() as u64 > maxmemory;
+    if !over() {
+        return Ok(vec![]);
+    }
+    if policy == Policy::NoEviction {
+        return Err(OOM);
+    }
+
+    // Ordered from least to most idle.
+    let mut pool: Vec<(Duration, usize, String)> = vec![];
+    let mut evicted = vec![];
+    while over() {
+        for (index, db) in databases.iter().enumerate() {
+            for (key, idle) in db.sample(SAMPLES).await {
+                pool.retain(|(_, db, candidate)| (*db, candidate) != (index, &key));
+                let position = pool.partition_point(|(other, ..)| *other < idle);
+                pool.insert(position, (idle, index, key));
+            }
+        }
+        if pool.len() > POOL_SIZE {
+            pool.drain(..pool.len() - POOL_SIZE);
+        }
+
+        // Nothing left to evict.
+        let Some((_, index, key)) = pool.pop() else {
+            break;
+        };
+        if databases.get(index).write(&key).await.del(&key) {
+            evicted.push((index, key));
+        }
+    }
+    Ok(evicted)
+}
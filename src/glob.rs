@@ -0,0 +1,107 @@
+/// Matches `string` against a Redis-style glob `pattern`, supporting `*`, `?`,
+/// `[abc]`/`[^abc]`/`[a-z]` character classes and `\` escapes.
+pub fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    match pattern.first() {
+        None => string.is_empty(),
+        Some(b'*') => {
+            let rest = &pattern[1..];
+            (0..=string.len()).any(|i| glob_match(rest, &string[i..]))
+        }
+        Some(b'?') => !string.is_empty() && glob_match(&pattern[1..], &string[1..]),
+        Some(b'[') => {
+            let Some(&c) = string.first() else {
+                return false;
+            };
+            match match_class(&pattern[1..], c) {
+                Some((matched, rest)) => matched && glob_match(rest, &string[1..]),
+                None => false,
+            }
+        }
+        Some(b'\\') if pattern.len() > 1 => {
+            string.first() == Some(&pattern[1]) && glob_match(&pattern[2..], &string[1..])
+        }
+        Some(&p) => string.first() == Some(&p) && glob_match(&pattern[1..], &string[1..]),
+    }
+}
+
+/// Matches `c` against the character class starting right after `[`, returning
+/// whether it matched and the remainder of the pattern after the closing `]`.
+fn match_class(pattern: &[u8], c: u8) -> Option<(bool, &[u8])> {
+    let (negate, mut i) = match pattern.first() {
+        Some(b'^') => (true, 1),
+        _ => (false, 0),
+    };
+    let mut matched = false;
+
+    loop {
+        match pattern.get(i)? {
+            b']' => break,
+            b'\\' => {
+                matched |= *pattern.get(i + 1)? == c;
+                i += 2;
+            }
+            &start
+                if pattern.get(i + 1) == Some(&b'-')
+                    && pattern.get(i + 2).is_some_and(|&b| b != b']') =>
+            {
+                let end = pattern[i + 2];
+                let (low, high) = if start <= end {
+                    (start, end)
+                } else {
+                    (end, start)
+                };
+                matched |= (low..=high).contains(&c);
+                i += 3;
+            }
+            &b => {
+                matched |= b == c;
+                i += 1;
+            }
+        }
+    }
+
+    Some((matched != negate, &pattern[i + 1..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::glob_match;
+
+    fn matches(pattern: &str, string: &str) -> bool {
+        glob_match(pattern.as_bytes(), string.as_bytes())
+    }
+
+    #[test]
+    fn question_mark_matches_one_character() {
+        assert!(matches("h?llo", "hello"));
+        assert!(matches("h?llo", "hallo"));
+        assert!(!matches("h?llo", "hllo"));
+        assert!(!matches("h?llo", "heello"));
+    }
+
+    #[test]
+    fn star_matches_any_run_of_characters() {
+        assert!(matches("h*llo", "hllo"));
+        assert!(matches("h*llo", "heeeello"));
+        assert!(!matches("h*llo", "hellon"));
+        assert!(matches("*", ""));
+    }
+
+    #[test]
+    fn class_matches_listed_characters() {
+        assert!(matches("h[ae]llo", "hello"));
+        assert!(matches("h[ae]llo", "hallo"));
+        assert!(!matches("h[ae]llo", "hillo"));
+        assert!(matches("h[^e]llo", "hallo"));
+        assert!(!matches("h[^e]llo", "hello"));
+        assert!(matches("h[a-b]llo", "hbllo"));
+        assert!(!matches("h[a-b]llo", "hcllo"));
+    }
+
+    #[test]
+    fn backslash_escapes_wildcards() {
+        assert!(matches(r"h\*llo", "h*llo"));
+        assert!(!matches(r"h\*llo", "hello"));
+        assert!(matches(r"h\?llo", "h?llo"));
+    }
+}
@@ -1,108 +1,463 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::task::Poll;
+use std::time::Duration;
 
 use anyhow::anyhow;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::RwLock;
+use tokio::sync::{Mutex, RwLock};
+use tokio::time::Instant;
 
+use crate::aof::{Aof, Fsync};
+use crate::args::{parse_args, parse_memory, Args, USAGE};
+use crate::clients::Clients;
+use crate::eviction::Policy;
+use crate::glob::glob_match;
+use crate::pubsub::{PubSub, Subscriber};
+use crate::replication::{is_write_command, Replication, REPLICATION_ID};
 use crate::resp::RespValue;
-use crate::storage::Storage;
+use crate::stats::Stats;
+use crate::storage::{Data, Databases, Db, Memory, Storage, Value, Waiter};
+use crate::stream::{Fields, Stream, StreamId};
+use crate::zset::SortedSet;
 
+mod aof;
+mod args;
+mod bitmap;
+mod clients;
+mod eviction;
+mod glob;
+mod pubsub;
+mod rdb;
+mod replication;
 mod resp;
+mod stats;
 mod storage;
+mod stream;
+mod zset;
+
+const EMPTY_RDB_HEX: &str = "524544495330303131fa0972656469732d76657205372e322e30fa0a72656469732d62697473c040fa056374696d65c26d08bc65fa08757365642d6d656dc2b0c41000fa08616f662d62617365c000fff06e3bfec0ff5aa2";
+
+const WRONGTYPE: &str = "WRONGTYPE Operation against a key holding the wrong kind of value";
+
+/// Keys the active expiry sweep looks at per lock acquisition.
+const EXPIRE_BATCH: usize = 20;
+
+/// Collections with more elements than this are freed in the background by
+/// UNLINK, as dropping them could otherwise hold up the connection.
+const LAZYFREE_THRESHOLD: usize = 64;
+
+/// The largest string SETRANGE can make, Redis's default `proto-max-bulk-len`.
+const MAX_STRING_LEN: usize = 512 * 1024 * 1024;
+
+/// Every command the server implements, reported by COMMAND, with its arity:
+/// the number of arguments including the command name, negated when it is
+/// only a minimum. Then come the positions of its first and last keys and
+/// the step between them, for COMMAND GETKEYS. A negative last key counts
+/// from the end, and commands without keys have all three at 0.
+const COMMANDS: &[(&str, i64, i64, i64, i64)] = &[
+    ("ping", -1, 0, 0, 0),
+    ("hello", -1, 0, 0, 0),
+    ("client", -2, 0, 0, 0),
+    ("echo", 2, 0, 0, 0),
+    ("select", 2, 0, 0, 0),
+    ("command", -1, 0, 0, 0),
+    ("multi", 1, 0, 0, 0),
+    ("exec", 1, 0, 0, 0),
+    ("discard", 1, 0, 0, 0),
+    ("watch", -2, 1, -1, 1),
+    ("unwatch", 1, 0, 0, 0),
+    ("subscribe", -2, 0, 0, 0),
+    ("unsubscribe", -1, 0, 0, 0),
+    ("psubscribe", -2, 0, 0, 0),
+    ("punsubscribe", -1, 0, 0, 0),
+    ("quit", -1, 0, 0, 0),
+    ("reset", 1, 0, 0, 0),
+    ("publish", 3, 0, 0, 0),
+    ("set", -3, 1, 1, 1),
+    ("setnx", 3, 1, 1, 1),
+    ("setex", 4, 1, 1, 1),
+    ("psetex", 4, 1, 1, 1),
+    ("get", 2, 1, 1, 1),
+    ("strlen", 2, 1, 1, 1),
+    ("getrange", 4, 1, 1, 1),
+    ("setrange", 4, 1, 1, 1),
+    ("getdel", 2, 1, 1, 1),
+    ("getset", 3, 1, 1, 1),
+    ("append", 3, 1, 1, 1),
+    ("setbit", 4, 1, 1, 1),
+    ("getbit", 3, 1, 1, 1),
+    ("bitcount", -2, 1, 1, 1),
+    ("mget", -2, 1, -1, 1),
+    ("mset", -3, 1, -1, 2),
+    ("del", -2, 1, -1, 1),
+    ("unlink", -2, 1, -1, 1),
+    ("exists", -2, 1, -1, 1),
+    ("touch", -2, 1, -1, 1),
+    ("incr", 2, 1, 1, 1),
+    ("decr", 2, 1, 1, 1),
+    ("incrby", 3, 1, 1, 1),
+    ("decrby", 3, 1, 1, 1),
+    ("incrbyfloat", 3, 1, 1, 1),
+    ("type", 2, 1, 1, 1),
+    ("object", -2, 2, 2, 1),
+    ("debug", -2, 0, 0, 0),
+    ("keys", 2, 0, 0, 0),
+    ("randomkey", 1, 0, 0, 0),
+    ("swapdb", 3, 0, 0, 0),
+    ("dbsize", 1, 0, 0, 0),
+    ("flushdb", -1, 0, 0, 0),
+    ("flushall", -1, 0, 0, 0),
+    ("scan", -2, 0, 0, 0),
+    ("expire", -3, 1, 1, 1),
+    ("pexpire", -3, 1, 1, 1),
+    ("expireat", -3, 1, 1, 1),
+    ("pexpireat", -3, 1, 1, 1),
+    ("persist", 2, 1, 1, 1),
+    ("rename", 3, 1, 2, 1),
+    ("renamenx", 3, 1, 2, 1),
+    ("copy", -3, 1, 2, 1),
+    ("move", 3, 1, 1, 1),
+    ("ttl", 2, 1, 1, 1),
+    ("pttl", 2, 1, 1, 1),
+    ("lpush", -3, 1, 1, 1),
+    ("rpush", -3, 1, 1, 1),
+    ("lpop", -2, 1, 1, 1),
+    ("rpop", -2, 1, 1, 1),
+    ("blpop", -3, 1, -2, 1),
+    ("llen", 2, 1, 1, 1),
+    ("lrange", 4, 1, 1, 1),
+    ("hset", -4, 1, 1, 1),
+    ("hget", 3, 1, 1, 1),
+    ("hgetall", 2, 1, 1, 1),
+    ("hdel", -3, 1, 1, 1),
+    ("hlen", 2, 1, 1, 1),
+    ("sadd", -3, 1, 1, 1),
+    ("srem", -3, 1, 1, 1),
+    ("smembers", 2, 1, 1, 1),
+    ("sismember", 3, 1, 1, 1),
+    ("zadd", -4, 1, 1, 1),
+    ("zrange", -4, 1, 1, 1),
+    ("zscore", 3, 1, 1, 1),
+    ("zrank", -3, 1, 1, 1),
+    ("xadd", -5, 1, 1, 1),
+    ("xrange", -4, 1, 1, 1),
+    ("xread", -4, 0, 0, 0),
+    ("info", -1, 0, 0, 0),
+    ("save", 1, 0, 0, 0),
+    ("bgsave", -1, 0, 0, 0),
+    ("config", -2, 0, 0, 0),
+    ("wait", 3, 0, 0, 0),
+    ("replconf", -1, 0, 0, 0),
+    ("psync", -3, 0, 0, 0),
+];
+
+struct ConnectionState {
+    /// The CLIENT ID of the connection, 0 for internal ones such as the
+    /// replication link or AOF replay.
+    id: u64,
+    protocol: u8,
+    /// The database selected with SELECT.
+    db: usize,
+    /// Commands queued since MULTI, until EXEC or DISCARD.
+    transaction: Option<Vec<RespValue>>,
+    /// Set when a command could not be queued, so that EXEC discards the
+    /// transaction.
+    aborted: bool,
+    /// Keys watched for EXEC with their database, and their versions at
+    /// WATCH time.
+    watched: Vec<(usize, String, u64)>,
+    /// Set while EXEC runs the queued commands, which must not block.
+    executing: bool,
+}
+
+impl ConnectionState {
+    fn new() -> Self {
+        ConnectionState {
+            id: 0,
+            protocol: 2,
+            db: 0,
+            transaction: None,
+            aborted: false,
+            watched: vec![],
+            executing: false,
+        }
+    }
+}
 
 struct Settings {
     port: u16,
-    replicaof: Option<String>,
+    replicaof: Option<(String, u16)>,
+    config: RwLock<Config>,
+    save_status: rdb::SaveStatus,
+    /// Opened on the first write once `appendonly` is enabled.
+    aof: Mutex<Option<Aof>>,
+    pubsub: Mutex<PubSub>,
+    clients: Arc<std::sync::Mutex<Clients>>,
+    /// Whether the background sweep evicts expired keys, toggled with DEBUG
+    /// SET-ACTIVE-EXPIRE.
+    active_expire: AtomicBool,
+    stats: Stats,
+    /// Held shared while a command runs, and exclusively by EXEC so that no
+    /// other command runs in the middle of a transaction.
+    transactions: RwLock<()>,
+}
+
+/// The runtime-tunable part of the settings, changed through `CONFIG SET`.
+#[derive(Clone)]
+struct Config {
+    dir: String,
+    dbfilename: String,
+    maxmemory: u64,
+    maxmemory_policy: Policy,
+    appendonly: bool,
+    appendfilename: String,
+    appendfsync: Fsync,
+}
+
+impl Config {
+    /// Parameters exposed through `CONFIG GET`, with their current values.
+    fn parameters(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("dir", self.dir.clone()),
+            ("dbfilename", self.dbfilename.clone()),
+            ("maxmemory", self.maxmemory.to_string()),
+            ("maxmemory-policy", self.maxmemory_policy.name().to_string()),
+            (
+                "appendonly",
+                if self.appendonly { "yes" } else { "no" }.to_string(),
+            ),
+            ("appendfilename", self.appendfilename.clone()),
+            ("appendfsync", self.appendfsync.name().to_string()),
+        ]
+    }
+
+    fn rdb_path(&self) -> PathBuf {
+        Path::new(&self.dir).join(&self.dbfilename)
+    }
+
+    fn aof_path(&self) -> PathBuf {
+        Path::new(&self.dir).join(&self.appendfilename)
+    }
+
+    fn set(&mut self, name: &str, value: &str) -> Result<(), String> {
+        match name {
+            "dir" => self.dir = value.to_string(),
+            "dbfilename" => self.dbfilename = value.to_string(),
+            "maxmemory" => {
+                self.maxmemory = parse_memory(value)
+                    .ok_or_else(|| "argument couldn't be parsed into an integer".to_string())?
+            }
+            "maxmemory-policy" => {
+                self.maxmemory_policy = Policy::parse(value)
+                    .ok_or_else(|| "argument must be 'noeviction' or 'allkeys-lru'".to_string())?
+            }
+            "appendonly" => {
+                self.appendonly = match value.to_ascii_lowercase().as_str() {
+                    "yes" => true,
+                    "no" => false,
+                    _ => return Err("argument must be 'yes' or 'no'".to_string()),
+                }
+            }
+            "appendfsync" => {
+                self.appendfsync = Fsync::parse(value)
+                    .ok_or_else(|| "argument must be 'always' or 'everysec'".to_string())?
+            }
+            _ => {
+                return Err(format!(
+                    "Unknown option or number of arguments for CONFIG SET - '{}'",
+                    name
+                ))
+            }
+        }
+        Ok(())
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
-    let args = std::env::args().collect::<Vec<String>>();
-    let args_hash = args
-        .iter()
-        .enumerate()
-        .map(|(i, arg)| (arg.clone(), i))
-        .collect::<std::collections::HashMap<String, usize>>();
-
-    let port = args_hash
-        .get("--port")
-        .map(|i| args[i + 1].parse::<u16>().unwrap())
-        .unwrap_or(6379);
+    let args = std::env::args().skip(1).collect::<Vec<String>>();
+    let Args {
+        port,
+        replicaof,
+        dir,
+        dbfilename,
+        appendonly,
+        appendfilename,
+        appendfsync,
+        databases,
+        maxmemory,
+        maxmemory_policy,
+    } = match parse_args(&args) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n{}", e, USAGE);
+            std::process::exit(1);
+        }
+    };
 
     let listener = TcpListener::bind(format!("127.0.0.1:{port}")).await?;
-    let replicaof = args_hash.get("--replicaof").map(|i| args[i + 1].clone());
-
-    let settings = Arc::new(Settings { port, replicaof });
-    let storage = Arc::new(RwLock::new(Storage::new()));
-
-    if let Some(replicaof) = &settings.replicaof {
-        let (host, replica_port) = replicaof.split_at(replicaof.find(' ').unwrap());
-        let replica_port = replica_port.trim().parse::<u16>().unwrap();
-        let mut stream = TcpStream::connect(format!("{}:{}", host, replica_port)).await?;
-        stream
-            .write_all(
-                &RespValue::Array(vec![RespValue::BulkString(Some(b"PING".to_vec()))]).to_bytes(),
-            )
-            .await?;
-        stream.flush().await?;
-        stream.read(&mut [0; 1024]).await?;
 
-        stream
-            .write_all(
-                &RespValue::Array(vec![
-                    RespValue::BulkString(Some(b"REPLCONF".to_vec())),
-                    RespValue::BulkString(Some(b"listening-port".to_vec())),
-                    RespValue::BulkString(Some(port.to_string().into_bytes())),
-                ])
-                .to_bytes(),
-            )
-            .await?;
-        stream.flush().await?;
-        stream.read(&mut [0; 1024]).await?;
+    let settings = Arc::new(Settings {
+        port,
+        replicaof,
+        config: RwLock::new(Config {
+            dir,
+            dbfilename,
+            maxmemory,
+            maxmemory_policy,
+            appendonly,
+            appendfilename,
+            appendfsync,
+        }),
+        save_status: rdb::SaveStatus::new(),
+        aof: Mutex::new(None),
+        pubsub: Mutex::new(PubSub::new()),
+        clients: Arc::new(std::sync::Mutex::new(Clients::new())),
+        active_expire: AtomicBool::new(true),
+        stats: Stats::new(),
+        transactions: RwLock::new(()),
+    });
+    let replication = Arc::new(RwLock::new(Replication::new()));
 
-        stream
-            .write_all(
-                &RespValue::Array(vec![
-                    RespValue::BulkString(Some(b"REPLCONF".to_vec())),
-                    RespValue::BulkString(Some(b"capa".to_vec())),
-                    RespValue::BulkString(Some(b"psync2".to_vec())),
-                ])
-                .to_bytes(),
-            )
-            .await?;
-        stream.flush().await?;
-        stream.read(&mut [0; 1024]).await?;
-
-        stream
-            .write_all(
-                &RespValue::Array(vec![
-                    RespValue::BulkString(Some(b"PSYNC".to_vec())),
-                    RespValue::BulkString(Some(b"?".to_vec())),
-                    RespValue::BulkString(Some(b"-1".to_vec())),
-                ])
-                .to_bytes(),
-            )
-            .await?;
-        stream.flush().await?;
-        stream.read(&mut [0; 1024]).await?;
+    let databases = load_databases(databases, &settings, &replication).await?;
+
+    let settings_fsync = settings.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            if let Some(aof) = settings_fsync.aof.lock().await.as_mut() {
+                if let Err(e) = aof.sync() {
+                    eprintln!("failed to sync append-only file: {}", e);
+                }
+            }
+        }
+    });
 
-        stream.flush().await?;
+    if let Some((host, port)) = &settings.replicaof {
+        sync_with_master(host, *port, &databases, &settings, &replication).await?;
     }
 
+    // Expired keys are otherwise only evicted when accessed. Evict them in
+    // small batches, releasing the lock in between to let commands run.
+    let databases_sweep = databases.clone();
+    let settings_sweep = settings.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_millis(100));
+        loop {
+            interval.tick().await;
+            if !settings_sweep.active_expire.load(Ordering::SeqCst) {
+                continue;
+            }
+            for shard in databases_sweep.iter().flat_map(|db| db.iter()) {
+                while shard.write().await.evict_expired(EXPIRE_BATCH) == EXPIRE_BATCH {
+                    tokio::task::yield_now().await;
+                }
+            }
+        }
+    });
+
+    let settings_stats = settings.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            settings_stats.stats.sample();
+        }
+    });
+
+    let replication_ack = replication.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            replication_ack.write().await.request_pending_ack();
+        }
+    });
+
     loop {
         let (stream, _) = listener.accept().await?;
 
-        let storage = storage.clone();
+        let databases = databases.clone();
         let settings = settings.clone();
+        let replication = replication.clone();
         tokio::spawn(async move {
-            handle_connection(stream, storage, settings).await;
+            handle_connection(stream, databases, settings, replication).await;
         });
     }
 }
 
+/// Connects to the master at `host:port` and completes the replication
+/// handshake, then applies the commands it streams in the background.
+async fn sync_with_master(
+    host: &str,
+    port: u16,
+    databases: &Arc<Databases>,
+    settings: &Arc<Settings>,
+    replication: &Arc<RwLock<Replication>>,
+) -> Result<(), anyhow::Error> {
+    let port_string = settings.port.to_string();
+    let stream = TcpStream::connect(format!("{}:{}", host, port)).await?;
+    let mut master = resp::RespParser::new(stream);
+
+    let handshake = [
+        vec!["PING"],
+        vec!["REPLCONF", "listening-port", &port_string],
+        vec!["REPLCONF", "capa", "psync2"],
+        vec!["PSYNC", "?", "-1"],
+    ];
+    let mut reply = RespValue::BulkString(None);
+    for command in handshake {
+        master
+            .write(RespValue::Array(
+                command
+                    .iter()
+                    .map(|arg| RespValue::BulkString(Some(arg.as_bytes().to_vec())))
+                    .collect(),
+            ))
+            .await?;
+        reply = master.parse().await?;
+        if let RespValue::Error(e) = reply {
+            return Err(anyhow!("replication handshake failed: {}", e));
+        }
+    }
+    // The stream that follows the snapshot starts at the offset the
+    // master replied `FULLRESYNC <replid> <offset>` with.
+    let offset = match &reply {
+        RespValue::SimpleString(reply) => match reply.split(' ').collect::<Vec<_>>()[..] {
+            ["FULLRESYNC", _, offset] => offset.parse().ok(),
+            _ => None,
+        },
+        _ => None,
+    }
+    .ok_or_else(|| anyhow!("unexpected reply to PSYNC: {:?}", reply))?;
+    replication.write().await.offset = offset;
+    master.parse_rdb().await?;
+
+    let databases = databases.clone();
+    let settings = settings.clone();
+    let replication = replication.clone();
+    tokio::spawn(async move {
+        replicate_from_master(master, databases, settings, replication).await;
+    });
+    Ok(())
+}
+
+/// Transfers an RDB snapshot to a replica. This is framed like a bulk string
+/// but, unlike one, is not followed by a trailing CRLF.
+async fn send_rdb(parser: &mut resp::RespParser, rdb: &[u8]) -> Result<(), anyhow::Error> {
+    parser
+        .write_all([format!("${}\r\n", rdb.len()).as_bytes(), rdb].concat())
+        .await
+}
+
 fn decode_hex_string(hex: &str) -> Result<Vec<u8>, anyhow::Error> {
-    if hex.len() % 2 != 0 {
+    if hex.len() % 2 == 1 {
         return Err(anyhow::anyhow!("Invalid hex string length"));
     }
     let mut binary_data = Vec::new();
@@ -116,47 +471,529 @@ fn decode_hex_string(hex: &str) -> Result<Vec<u8>, anyhow::Error> {
 
 async fn handle_connection(
     stream: tokio::net::TcpStream,
-    storage: Arc<RwLock<Storage>>,
+    databases: Arc<Databases>,
     settings: Arc<Settings>,
+    replication: Arc<RwLock<Replication>>,
 ) {
-    println!("accepted new connection");
+    let addr = stream
+        .peer_addr()
+        .map(|addr| addr.to_string())
+        .unwrap_or_default();
+    let registration = Clients::register(&settings.clients, addr);
+    settings
+        .stats
+        .connections_received
+        .fetch_add(1, Ordering::SeqCst);
+    let mut resp_parser = resp::RespParser::new(stream);
+    let mut state = ConnectionState::new();
+    state.id = registration.id;
 
-    tokio::spawn(async move {
-        let mut resp_parser = resp::RespParser::new(stream);
+    let (mut subscriber, mut messages) = Subscriber::new();
 
-        loop {
-            let value = resp_parser.parse().await.unwrap();
+    let (replica_id, mut commands) = loop {
+        let value = tokio::select! {
+            value = resp_parser.parse() => match value {
+                Ok(value) => value,
+                // The client went away, there is nobody left to tell.
+                Err(e) if e.is::<resp::Closed>() || e.is::<std::io::Error>() => return,
+                // The rest of the input cannot be trusted to be in sync.
+                Err(e) => {
+                    let error = RespValue::Error(format!("ERR Protocol error: {}", e));
+                    let _ = resp_parser.write(error).await;
+                    let _ = resp_parser.flush().await;
+                    return;
+                }
+            },
+            Some(message) = messages.recv() => {
+                let message = match state.protocol {
+                    3 => message.into_resp3(),
+                    _ => message.into_resp2(),
+                };
+                if resp_parser.write(message).await.is_err()
+                    || resp_parser.flush().await.is_err()
+                {
+                    return;
+                }
+                continue;
+            }
+        };
 
-            let (command, result) = match parse_command(value) {
-                Ok((command, args)) => (
-                    command.clone(),
-                    handle_command(command, args, storage.clone(), settings.clone()).await,
-                ),
-                Err(e) => ("".to_string(), RespValue::Error(e.to_string())),
+        let (command, results) = match parse_command(value.clone()) {
+            Ok((command, args)) => {
+                // Without RESP3 pushes, replies could not be told apart
+                // from messages, so RESP2 subscribers are restricted.
+                let subscribed = subscriber.count() > 0 && state.protocol != 3;
+                let mut checked = check_arity(&command, &args);
+                if state.transaction.is_some() {
+                    if checked.is_ok() && !COMMANDS.iter().any(|(name, ..)| *name == command) {
+                        checked = Err(RespValue::Error("ERR unknown command".to_string()));
+                    }
+                    state.aborted |= checked.is_err();
+                }
+                let results = match (checked, state.transaction.as_mut()) {
+                    (Err(e), _) => vec![e],
+                    (_, _) if subscribed && !is_subscribed_command(&command) => {
+                        vec![RespValue::Error(format!(
+                            "ERR Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context",
+                            command
+                        ))]
+                    }
+                    (_, _) if subscribed && command == "ping" => {
+                        vec![RespValue::Array(vec![
+                            RespValue::BulkString(Some(b"pong".to_vec())),
+                            RespValue::BulkString(Some(
+                                args.first()
+                                    .and_then(arg_string)
+                                    .unwrap_or_default()
+                                    .into_bytes(),
+                            )),
+                        ])]
+                    }
+                    (_, Some(queued))
+                        if !matches!(
+                            command.as_str(),
+                            "exec" | "discard" | "multi" | "watch" | "quit" | "reset"
+                        ) =>
+                    {
+                        queued.push(value);
+                        vec![RespValue::SimpleString("QUEUED".to_string())]
+                    }
+                    (_, _)
+                        if matches!(
+                            command.as_str(),
+                            "subscribe" | "unsubscribe" | "psubscribe" | "punsubscribe"
+                        ) =>
+                    {
+                        let mut pubsub = settings.pubsub.lock().await;
+                        subscription(&command, &args, &mut subscriber, &mut pubsub)
+                    }
+                    (_, _) if command == "exec" => {
+                        vec![exec(&databases, &settings, &replication, &mut state).await]
+                    }
+                    (_, _) if command == "quit" => vec![RespValue::SimpleString("OK".to_string())],
+                    (_, _) if command == "reset" => {
+                        subscriber.unsubscribe_all();
+                        settings.clients.lock().unwrap().set_name(state.id, None);
+                        state = ConnectionState {
+                            id: state.id,
+                            ..ConnectionState::new()
+                        };
+                        vec![RespValue::SimpleString("RESET".to_string())]
+                    }
+                    _ => vec![
+                        execute(
+                            command.clone(),
+                            args,
+                            &value,
+                            &databases,
+                            &settings,
+                            &replication,
+                            &mut state,
+                        )
+                        .await,
+                    ],
+                };
+                (command, results)
+            }
+            Err(e) => ("".to_string(), vec![RespValue::Error(format!("ERR {}", e))]),
+        };
+        for result in results {
+            let result = match state.protocol {
+                3 => result.into_resp3(),
+                _ => result.into_resp2(),
             };
+            if resp_parser.write(result).await.is_err() {
+                return;
+            }
+        }
+
+        // QUIT closes the connection once its reply is out.
+        if command == "quit" {
+            let _ = resp_parser.flush().await;
+            return;
+        }
 
-            resp_parser.write(result).await.unwrap();
+        if command == "psync" {
+            let replica = replication.write().await.register();
+            let empty_rdb = decode_hex_string(EMPTY_RDB_HEX).unwrap();
+            if send_rdb(&mut resp_parser, &empty_rdb).await.is_err()
+                || resp_parser.flush().await.is_err()
+            {
+                return;
+            }
+            break replica;
+        }
+    };
 
-            match command.to_lowercase().as_str() {
-                "psync" => {
-                    let hardcoded_empty_rdb_file_hex = "524544495330303131fa0972656469732d76657205372e322e30fa0a72656469732d62697473c040fa056374696d65c26d08bc65fa08757365642d6d656dc2b0c41000fa08616f662d62617365c000fff06e3bfec0ff5aa2";
-                    let binary_empty_rdb = decode_hex_string(hardcoded_empty_rdb_file_hex).unwrap();
-                    let len = binary_empty_rdb.len();
-                    resp_parser
-                        .write_all([format!("${}\r\n", len).as_bytes(), &binary_empty_rdb].concat())
-                        .await
-                        .unwrap();
+    // The connection belongs to a replica from here on: stop serving
+    // requests and stream it every write applied on this master instead,
+    // only listening for its acknowledgements.
+    loop {
+        tokio::select! {
+            bytes = commands.recv() => match bytes {
+                Some(bytes) => {
+                    if resp_parser.write_all(bytes).await.is_err()
+                        || resp_parser.flush().await.is_err()
+                    {
+                        break;
+                    }
                 }
-                _ => {}
+                None => break,
+            },
+            value = resp_parser.parse() => match value.map(parse_command) {
+                Ok(Ok((command, args))) if command == "replconf" => {
+                    if let [subcommand, offset] = args.as_slice() {
+                        let is_ack = arg_string(subcommand)
+                            .is_some_and(|s| s.eq_ignore_ascii_case("ack"));
+                        if let (true, Some(offset)) = (is_ack, arg_int(offset)) {
+                            replication
+                                .write()
+                                .await
+                                .acknowledge(replica_id, offset as usize);
+                        }
+                    }
+                }
+                Ok(_) => {}
+                Err(_) => break,
+            },
+        }
+    }
+}
+
+/// Returns the command to replicate and persist for `value`, if it wrote to
+/// the keyspace. A BLPOP that popped an element is rewritten as the LPOP it
+/// amounted to, so replicas and AOF replays never block.
+fn propagated_write(command: &str, value: &RespValue, result: &RespValue) -> Option<RespValue> {
+    match (command, result) {
+        ("blpop", RespValue::Array(popped)) => Some(RespValue::Array(vec![
+            RespValue::BulkString(Some(b"LPOP".to_vec())),
+            popped[0].clone(),
+        ])),
+        _ if is_write_command(command) && !matches!(result, RespValue::Error(_)) => {
+            Some(value.clone())
+        }
+        _ => None,
+    }
+}
+
+/// Appends a successful write command that ran against database `db` to the
+/// append-only file, if enabled.
+async fn append_to_aof(settings: &Settings, db: usize, command: &RespValue) {
+    let (appendonly, path, fsync) = {
+        let config = settings.config.read().await;
+        (config.appendonly, config.aof_path(), config.appendfsync)
+    };
+    if !appendonly {
+        return;
+    }
+
+    let mut aof = settings.aof.lock().await;
+    if aof.is_none() {
+        match Aof::open(&path) {
+            Ok(opened) => *aof = Some(opened),
+            Err(e) => {
+                eprintln!("failed to open append-only file: {}", e);
+                return;
             }
         }
-    });
+    }
+    if let Some(aof) = aof.as_mut() {
+        if let Err(e) = aof.append(db, command, fsync) {
+            eprintln!("failed to write to append-only file: {}", e);
+        }
+    }
+}
+
+/// Commands a RESP2 connection may still send once it has subscribed.
+fn is_subscribed_command(command: &str) -> bool {
+    matches!(
+        command,
+        "subscribe" | "unsubscribe" | "psubscribe" | "punsubscribe" | "ping" | "quit" | "reset"
+    )
+}
+
+/// Handles (P)SUBSCRIBE and (P)UNSUBSCRIBE, which confirm every channel or
+/// pattern with a separate push carrying the connection's subscription count.
+fn subscription(
+    command: &str,
+    args: &[RespValue],
+    subscriber: &mut Subscriber,
+    pubsub: &mut PubSub,
+) -> Vec<RespValue> {
+    let mut channels = args.iter().filter_map(arg_string).collect::<Vec<_>>();
+    if channels.is_empty() {
+        channels = match command {
+            "unsubscribe" => subscriber.channels(),
+            "punsubscribe" => subscriber.patterns(),
+            _ => {
+                return vec![RespValue::Error(
+                    "ERR wrong number of arguments".to_string(),
+                )]
+            }
+        };
+    }
+
+    let confirm = |channel: Option<String>, count: usize| {
+        RespValue::Push(vec![
+            RespValue::BulkString(Some(command.as_bytes().to_vec())),
+            RespValue::BulkString(channel.map(String::into_bytes)),
+            RespValue::Integer(count as i64),
+        ])
+    };
+    if channels.is_empty() {
+        return vec![confirm(None, subscriber.count())];
+    }
+
+    channels
+        .into_iter()
+        .map(|channel| {
+            match command {
+                "subscribe" => subscriber.subscribe(pubsub, &channel),
+                "psubscribe" => subscriber.psubscribe(pubsub, &channel),
+                "punsubscribe" => subscriber.punsubscribe(&channel),
+                _ => subscriber.unsubscribe(&channel),
+            }
+            confirm(Some(channel), subscriber.count())
+        })
+        .collect()
+}
+
+/// Creates `count` databases, rebuilt from the append-only file if it is
+/// enabled and exists, or else from the snapshot if there is one.
+async fn load_databases(
+    count: usize,
+    settings: &Arc<Settings>,
+    replication: &Arc<RwLock<Replication>>,
+) -> Result<Arc<Databases>, anyhow::Error> {
+    let (appendonly, aof_path, rdb_path, maxmemory) = {
+        let config = settings.config.read().await;
+        (
+            config.appendonly,
+            config.aof_path(),
+            config.rdb_path(),
+            config.maxmemory,
+        )
+    };
+    // The append-only file is more up to date than any snapshot.
+    let replay_aof = appendonly && aof_path.exists();
+
+    let memory = Arc::new(Memory::new());
+    let mut databases = (0..count).map(|_| Db::new(&memory)).collect::<Vec<_>>();
+    if !replay_aof && rdb_path.exists() {
+        rdb::load(&rdb_path, &mut databases)?;
+    }
+    let databases = Arc::new(Databases::new(databases, memory));
+    databases.track_memory(maxmemory > 0).await;
+
+    if replay_aof {
+        let mut state = ConnectionState::new();
+        for value in aof::load(&aof_path)? {
+            if let Ok((command, args)) = parse_command(value) {
+                handle_command(
+                    command,
+                    args,
+                    databases.clone(),
+                    settings.clone(),
+                    replication.clone(),
+                    &mut state,
+                )
+                .await;
+            }
+        }
+    }
+    Ok(databases)
+}
+
+/// Runs a command from a client, then replicates and persists it if it wrote
+/// to the keyspace.
+async fn execute(
+    command: String,
+    args: Vec<RespValue>,
+    value: &RespValue,
+    databases: &Arc<Databases>,
+    settings: &Arc<Settings>,
+    replication: &Arc<RwLock<Replication>>,
+    state: &mut ConnectionState,
+) -> RespValue {
+    settings
+        .stats
+        .commands_processed
+        .fetch_add(1, Ordering::SeqCst);
+
+    // Blocking commands take it around each attempt instead, so as not to
+    // hold up transactions while they wait. CONFIG takes it exclusively, and
+    // DEBUG never writes but may sleep.
+    let _shared = match state.executing
+        || is_blocking_command(&command)
+        || matches!(command.as_str(), "config" | "debug")
+    {
+        true => None,
+        false => Some(settings.transactions.read().await),
+    };
+
+    if eviction::may_grow(&command) {
+        if let Err(e) = make_room(databases, settings, replication).await {
+            return e;
+        }
+    }
+
+    let result = handle_command(
+        command.clone(),
+        args,
+        databases.clone(),
+        settings.clone(),
+        replication.clone(),
+        state,
+    )
+    .await;
+
+    if let Some(write) = propagated_write(&command, value, &result) {
+        replication.write().await.propagate(state.db, &write);
+        append_to_aof(settings, state.db, &write).await;
+    }
+    result
+}
+
+/// Evicts keys if the keyspace went over `maxmemory`, replicating and
+/// persisting their removal. Fails if the policy does not allow evicting.
+async fn make_room(
+    databases: &Databases,
+    settings: &Settings,
+    replication: &RwLock<Replication>,
+) -> Result<(), RespValue> {
+    let (maxmemory, policy) = {
+        let config = settings.config.read().await;
+        (config.maxmemory, config.maxmemory_policy)
+    };
+    if maxmemory == 0 {
+        return Ok(());
+    }
+
+    let evicted = eviction::evict(databases, maxmemory, policy)
+        .await
+        .map_err(|e| RespValue::Error(e.to_string()))?;
+    for (db, key) in evicted {
+        let del = RespValue::Array(vec![
+            RespValue::BulkString(Some(b"DEL".to_vec())),
+            RespValue::BulkString(Some(key.into_bytes())),
+        ]);
+        replication.write().await.propagate(db, &del);
+        append_to_aof(settings, db, &del).await;
+    }
+    Ok(())
+}
+
+/// Commands that may wait for other clients, for replicas or for a timeout.
+fn is_blocking_command(command: &str) -> bool {
+    matches!(command, "blpop" | "xread" | "wait")
+}
+
+/// Runs the commands queued since MULTI, unless a watched key was modified
+/// in the meantime. No other command runs from the check until the last
+/// queued command is done.
+async fn exec(
+    databases: &Arc<Databases>,
+    settings: &Arc<Settings>,
+    replication: &Arc<RwLock<Replication>>,
+    state: &mut ConnectionState,
+) -> RespValue {
+    let Some(queued) = state.transaction.take() else {
+        return RespValue::Error("ERR EXEC without MULTI".to_string());
+    };
+
+    let _exclusive = settings.transactions.write().await;
+    let watched = std::mem::take(&mut state.watched);
+    if state.aborted {
+        return RespValue::Error(
+            "EXECABORT Transaction discarded because of previous errors.".to_string(),
+        );
+    }
+    for (db, key, version) in watched {
+        if databases.get(db).write(&key).await.version(&key) != version {
+            return RespValue::NullArray;
+        }
+    }
+
+    state.executing = true;
+    let mut results = vec![];
+    for value in queued {
+        let result = match parse_command(value.clone()) {
+            Ok((command, args)) => {
+                execute(
+                    command,
+                    args,
+                    &value,
+                    databases,
+                    settings,
+                    replication,
+                    state,
+                )
+                .await
+            }
+            Err(e) => RespValue::Error(format!("ERR {}", e)),
+        };
+        results.push(result);
+    }
+    state.executing = false;
+    RespValue::Array(results)
+}
+
+/// Applies the command stream received from the master to the local storage.
+/// Replies are not sent back: the master does not expect them.
+async fn replicate_from_master(
+    mut master: resp::RespParser,
+    databases: Arc<Databases>,
+    settings: Arc<Settings>,
+    replication: Arc<RwLock<Replication>>,
+) {
+    let mut state = ConnectionState::new();
+
+    while let Ok(value) = master.parse().await {
+        let len = value.to_bytes().len();
+
+        if let Ok((command, args)) = parse_command(value.clone()) {
+            let is_getack = command == "replconf"
+                && args
+                    .first()
+                    .and_then(arg_string)
+                    .is_some_and(|s| s.eq_ignore_ascii_case("getack"));
+
+            if is_getack {
+                let offset = replication.read().await.offset;
+                let ack = RespValue::Array(vec![
+                    RespValue::BulkString(Some(b"REPLCONF".to_vec())),
+                    RespValue::BulkString(Some(b"ACK".to_vec())),
+                    RespValue::BulkString(Some(offset.to_string().into_bytes())),
+                ]);
+                if master.write(ack).await.is_err() {
+                    break;
+                }
+            } else {
+                let write = is_write_command(&command);
+                let _shared = settings.transactions.read().await;
+                let result = handle_command(
+                    command,
+                    args,
+                    databases.clone(),
+                    settings.clone(),
+                    replication.clone(),
+                    &mut state,
+                )
+                .await;
+                if write && !matches!(result, RespValue::Error(_)) {
+                    append_to_aof(&settings, state.db, &value).await;
+                }
+            }
+        }
+
+        replication.write().await.offset += len;
+    }
 }
 
 fn parse_command(value: RespValue) -> Result<(String, Vec<RespValue>), anyhow::Error> {
     match value {
         RespValue::Array(a) => {
-            let command = match a.first().unwrap().clone() {
+            let command = match a.first().cloned().unwrap_or(RespValue::BulkString(None)) {
                 RespValue::BulkString(Some(s)) => String::from_utf8(s.clone())?.to_lowercase(),
                 _ => return Err(anyhow!("Expected bulk string")),
             };
@@ -168,81 +1005,3096 @@ fn parse_command(value: RespValue) -> Result<(String, Vec<RespValue>), anyhow::E
     }
 }
 
+fn arg_string(arg: &RespValue) -> Option<String> {
+    match arg {
+        RespValue::BulkString(Some(s)) => String::from_utf8(s.clone()).ok(),
+        _ => None,
+    }
+}
+
+fn arg_int(arg: &RespValue) -> Option<i64> {
+    arg_string(arg)?.parse::<i64>().ok()
+}
+
+/// Checks the number of arguments a known command was given against its
+/// arity, so the handlers can rely on their required arguments.
+fn check_arity(command: &str, args: &[RespValue]) -> Result<(), RespValue> {
+    let Some(&(_, arity, ..)) = COMMANDS.iter().find(|(name, ..)| *name == command) else {
+        return Ok(());
+    };
+    let len = args.len() as i64 + 1;
+    match arity >= 0 {
+        true if len == arity => Ok(()),
+        false if len >= -arity => Ok(()),
+        _ => Err(RespValue::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            command
+        ))),
+    }
+}
+
+/// The indices in `args` of the keys a command would access, as reported by
+/// COMMAND GETKEYS.
+fn key_positions(
+    command: &str,
+    args: &[RespValue],
+) -> Result<std::iter::StepBy<std::ops::RangeInclusive<usize>>, RespValue> {
+    let Some(&(_, _, first, last, step)) = COMMANDS.iter().find(|(name, ..)| *name == command)
+    else {
+        return Err(RespValue::Error(
+            "ERR Invalid command specified".to_string(),
+        ));
+    };
+    if check_arity(command, args).is_err() {
+        return Err(RespValue::Error(
+            "ERR Invalid arguments specified for command".to_string(),
+        ));
+    }
+
+    // XREAD takes its keys after STREAMS, followed by as many IDs.
+    let (first, last, step) = match command {
+        "xread" => {
+            let streams = args.iter().position(|arg| {
+                arg_string(arg).is_some_and(|arg| arg.eq_ignore_ascii_case("streams"))
+            });
+            let Some(streams) = streams else {
+                return Err(RespValue::Error(
+                    "ERR Invalid arguments specified for command".to_string(),
+                ));
+            };
+            let ids = args.len() - streams - 1;
+            if ids == 0 || ids % 2 == 1 {
+                return Err(RespValue::Error(
+                    "ERR Invalid arguments specified for command".to_string(),
+                ));
+            }
+            (streams as i64 + 2, (streams + ids / 2) as i64 + 1, 1)
+        }
+        _ => (first, last, step),
+    };
+    if first == 0 {
+        return Err(RespValue::Error(
+            "ERR The command has no key arguments".to_string(),
+        ));
+    }
+
+    // Positions count the command name, which `args` does not include.
+    let argc = args.len() as i64 + 1;
+    let last = match last < 0 {
+        // Every key needs its step's worth of arguments, like MSET's values.
+        true if (argc - first) % step != 0 => {
+            return Err(RespValue::Error(
+                "ERR Invalid arguments specified for command".to_string(),
+            ))
+        }
+        true => argc + last,
+        false => last.min(argc - 1),
+    };
+    Ok((first as usize - 1..=last as usize - 1).step_by(step as usize))
+}
+
+/// Checks that the keys `command` accesses are valid UTF-8, as keys are
+/// stored as strings. Handlers can then unwrap `arg_string` on them.
+fn check_keys(command: &str, args: &[RespValue]) -> Result<(), RespValue> {
+    let Ok(mut positions) = key_positions(command, args) else {
+        return Ok(());
+    };
+    match positions.all(|i| arg_string(&args[i]).is_some()) {
+        true => Ok(()),
+        false => Err(RespValue::Error(
+            "ERR invalid key: keys must be valid UTF-8".to_string(),
+        )),
+    }
+}
+
+/// Parses a database index given to SELECT and friends.
+fn db_index(arg: &RespValue, databases: &Databases) -> Result<usize, RespValue> {
+    match arg_int(arg) {
+        Some(index) if (0..databases.len() as i64).contains(&index) => Ok(index as usize),
+        Some(_) => Err(RespValue::Error("ERR DB index is out of range".to_string())),
+        None => Err(RespValue::Error(
+            "ERR value is not an integer or out of range".to_string(),
+        )),
+    }
+}
+
+/// The string held by `value`, or the WRONGTYPE error for any other type.
+fn expect_string(value: &Value) -> Result<&[u8], RespValue> {
+    match value {
+        Value::String(value) => Ok(value),
+        _ => Err(RespValue::Error(WRONGTYPE.to_string())),
+    }
+}
+
+/// Like `expect_string`, for commands modifying the string in place.
+fn expect_string_mut(value: &mut Value) -> Result<&mut Vec<u8>, RespValue> {
+    match value {
+        Value::String(value) => Ok(Arc::make_mut(value)),
+        _ => Err(RespValue::Error(WRONGTYPE.to_string())),
+    }
+}
+
+/// Parses the string at `key` for INCR and friends: `Ok(None)` if it is not
+/// a number, and zero if the key does not exist.
+fn stored_number<T: FromStr + Default>(
+    storage: &mut Storage,
+    key: &str,
+) -> Result<Option<T>, RespValue> {
+    match storage.lookup(key).map(expect_string).transpose()? {
+        Some(value) => Ok(std::str::from_utf8(value)
+            .ok()
+            .and_then(|value| value.parse().ok())),
+        None => Ok(Some(T::default())),
+    }
+}
+
+/// Stores the result of INCR and friends, keeping the key's time to live.
+fn store_number(storage: &mut Storage, key: String, number: String) {
+    let value = Value::String(Arc::new(number.into_bytes()));
+    match storage.get_mut(&key) {
+        Some(data) => data.value = value,
+        None => storage.set(key, value, None),
+    }
+}
+
+/// The instant `millis` milliseconds from now, unless that overflows.
+fn deadline_in(millis: u64) -> Option<Instant> {
+    Instant::now().checked_add(Duration::from_millis(millis))
+}
+
+fn arg_float(arg: &RespValue) -> Option<f64> {
+    arg_string(arg)?
+        .parse::<f64>()
+        .ok()
+        .filter(|value| !value.is_nan())
+}
+
+/// Encodes a stream entry as `[id, [field, value, ...]]`.
+fn stream_entry(id: &StreamId, fields: &Fields) -> RespValue {
+    RespValue::Array(vec![
+        RespValue::BulkString(Some(id.to_string().into_bytes())),
+        RespValue::Array(
+            fields
+                .iter()
+                .flat_map(|(field, value)| {
+                    [
+                        RespValue::BulkString(Some(field.clone())),
+                        RespValue::BulkString(Some(value.clone())),
+                    ]
+                })
+                .collect(),
+        ),
+    ])
+}
+
+/// Resolves once any of `waiters` is notified. The `Notified` futures are
+/// created before this returns, so call it before releasing the storage lock
+/// to not miss a write.
+fn any_notified(waiters: &[Waiter]) -> impl Future<Output = ()> + '_ {
+    let mut notified = waiters
+        .iter()
+        .map(|waiter| Box::pin(waiter.notify().notified()))
+        .collect::<Vec<_>>();
+    std::future::poll_fn(move |cx| {
+        match notified
+            .iter_mut()
+            .any(|notified| notified.as_mut().poll(cx).is_ready())
+        {
+            true => Poll::Ready(()),
+            false => Poll::Pending,
+        }
+    })
+}
+
+/// The sections INFO reports when not asked for specific ones.
+const INFO_SECTIONS: [&str; 5] = ["server", "replication", "persistence", "stats", "keyspace"];
+
+/// Renders one INFO section, or `None` if there is no such section.
+async fn info_section(
+    section: &str,
+    databases: &Databases,
+    settings: &Settings,
+    replication: &RwLock<Replication>,
+) -> Option<String> {
+    let info = match section {
+        "server" => "# Server\nversion:0.0.1\n".to_string(),
+        "replication" => {
+            let role = match settings.replicaof {
+                Some(_) => "slave",
+                None => "master",
+            };
+
+            let offset = replication.read().await.offset;
+
+            format!(
+                "# Replication\nrole:{}\nmaster_replid:{}\nmaster_repl_offset:{}\n",
+                role, REPLICATION_ID, offset
+            )
+        }
+        "stats" => {
+            let expired_keys = databases
+                .read_all()
+                .await
+                .iter()
+                .flatten()
+                .map(|shard| shard.expired_keys)
+                .sum::<u64>();
+            format!(
+                "# Stats\n{}expired_keys:{}\n",
+                settings.stats.info(),
+                expired_keys
+            )
+        }
+        "persistence" => {
+            let status = &settings.save_status;
+
+            format!(
+                "# Persistence\nrdb_bgsave_in_progress:{}\nrdb_last_save_time:{}\n",
+                status.bgsave_in_progress.load(Ordering::SeqCst) as u8,
+                status.last_save_time.load(Ordering::SeqCst)
+            )
+        }
+        "keyspace" => {
+            let mut info = "# Keyspace\n".to_string();
+            for (index, shards) in databases.read_all().await.iter().enumerate() {
+                let keys = shards.iter().map(|shard| shard.len_live()).sum::<usize>();
+                let expires = shards
+                    .iter()
+                    .map(|shard| shard.expires_live())
+                    .sum::<usize>();
+                if keys > 0 {
+                    info += &format!("db{}:keys={},expires={},avg_ttl=0\n", index, keys, expires);
+                }
+            }
+            info
+        }
+        _ => return None,
+    };
+    Some(info)
+}
+
 async fn handle_command(
     command: String,
     args: Vec<RespValue>,
-    storage: Arc<RwLock<Storage>>,
+    databases: Arc<Databases>,
     settings: Arc<Settings>,
-) -> (RespValue) {
-    match command.as_str() {
+    replication: Arc<RwLock<Replication>>,
+    state: &mut ConnectionState,
+) -> RespValue {
+    // Commands replicated from the master or replayed from the AOF arrive
+    // here without going through `handle_connection`'s checks.
+    if let Err(e) = check_arity(&command, &args).and_then(|()| check_keys(&command, &args)) {
+        return e;
+    }
+
+    let db = databases.get(state.db);
+    match command.as_str() {
         "ping" => RespValue::SimpleString("PONG".to_string()),
-        "echo" => args.first().unwrap().clone(),
-        "set" => match args.as_slice() {
-            [key, value] => {
-                let mut storage = storage.write().await;
-                storage.set(
-                    String::from_utf8(key.to_bytes().clone()).unwrap(),
-                    value.clone(),
-                    None,
-                );
+        "hello" => {
+            if let Some(protover) = args.first() {
+                match arg_int(protover) {
+                    Some(protover @ 2..=3) => state.protocol = protover as u8,
+                    _ => {
+                        return RespValue::Error("NOPROTO unsupported protocol version".to_string())
+                    }
+                }
+            }
+
+            let role = match settings.replicaof {
+                Some(_) => "replica",
+                None => "master",
+            };
+            let property = |name: &str, value: RespValue| {
+                (RespValue::BulkString(Some(name.as_bytes().to_vec())), value)
+            };
+
+            RespValue::Map(vec![
+                property("server", RespValue::BulkString(Some(b"redis".to_vec()))),
+                property("version", RespValue::BulkString(Some(b"0.0.1".to_vec()))),
+                property("proto", RespValue::Integer(state.protocol as i64)),
+                property("mode", RespValue::BulkString(Some(b"standalone".to_vec()))),
+                property(
+                    "role",
+                    RespValue::BulkString(Some(role.as_bytes().to_vec())),
+                ),
+                property("modules", RespValue::Array(vec![])),
+            ])
+        }
+        "multi" => match state.transaction {
+            Some(_) => RespValue::Error("ERR MULTI calls can not be nested".to_string()),
+            None => {
+                state.transaction = Some(vec![]);
+                state.aborted = false;
                 RespValue::SimpleString("OK".to_string())
             }
-            [key, value, RespValue::BulkString(Some(argument)), RespValue::BulkString(Some(expiry))] => {
-                match String::from_utf8(argument.clone())
-                    .unwrap()
-                    .to_ascii_lowercase()
-                    .as_str()
-                {
-                    "px" => {
-                        let expiry = String::from_utf8(expiry.clone())
-                            .unwrap()
-                            .parse::<usize>()
-                            .unwrap();
-                        let mut storage = storage.write().await;
-                        storage.set(
-                            String::from_utf8(key.to_bytes().clone()).unwrap(),
-                            value.clone(),
-                            Some(expiry),
+        },
+        "discard" => match state.transaction.take() {
+            Some(_) => {
+                state.watched.clear();
+                RespValue::SimpleString("OK".to_string())
+            }
+            None => RespValue::Error("ERR DISCARD without MULTI".to_string()),
+        },
+        "watch" => match args.as_slice() {
+            _ if state.transaction.is_some() => {
+                RespValue::Error("ERR WATCH inside MULTI is not allowed".to_string())
+            }
+            [] => RespValue::Error("ERR wrong number of arguments".to_string()),
+            keys => {
+                for key in keys.iter().filter_map(arg_string) {
+                    let version = db.write(&key).await.version(&key);
+                    state.watched.push((state.db, key, version));
+                }
+                RespValue::SimpleString("OK".to_string())
+            }
+        },
+        "unwatch" => {
+            state.watched.clear();
+            RespValue::SimpleString("OK".to_string())
+        }
+        "publish" => match args.as_slice() {
+            [channel, RespValue::BulkString(Some(message))] => {
+                // Nobody can be subscribed to a channel that is not UTF-8.
+                let Some(channel) = arg_string(channel) else {
+                    return RespValue::Integer(0);
+                };
+                let received = settings
+                    .pubsub
+                    .lock()
+                    .await
+                    .publish(&channel, message.clone());
+                RespValue::Integer(received as i64)
+            }
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "command" => match args.first().and_then(arg_string) {
+            None => RespValue::Array(
+                COMMANDS
+                    .iter()
+                    .map(|(name, ..)| RespValue::BulkString(Some(name.as_bytes().to_vec())))
+                    .collect(),
+            ),
+            Some(subcommand) => match subcommand.to_ascii_lowercase().as_str() {
+                "count" => RespValue::Integer(COMMANDS.len() as i64),
+                "getkeys" => match args.get(1).and_then(arg_string) {
+                    Some(command) => {
+                        let args = &args[2..];
+                        match key_positions(&command.to_ascii_lowercase(), args) {
+                            Ok(positions) => {
+                                RespValue::Array(positions.map(|i| args[i].clone()).collect())
+                            }
+                            Err(e) => e,
+                        }
+                    }
+                    None => RespValue::Error("ERR wrong number of arguments".to_string()),
+                },
+                "docs" => RespValue::Map(vec![]),
+                _ => RespValue::Error("ERR unknown subcommand".to_string()),
+            },
+        },
+        "client" => {
+            let subcommand = args
+                .first()
+                .and_then(arg_string)
+                .unwrap_or_default()
+                .to_ascii_lowercase();
+            match (subcommand.as_str(), args.get(1..).unwrap_or_default()) {
+                ("id", []) => RespValue::Integer(state.id as i64),
+                ("getname", []) => RespValue::BulkString(
+                    settings
+                        .clients
+                        .lock()
+                        .unwrap()
+                        .name(state.id)
+                        .map(String::into_bytes),
+                ),
+                ("setname", [name]) => {
+                    let name = arg_string(name).unwrap_or_default();
+                    if name.bytes().any(|b| !(b'!'..=b'~').contains(&b)) {
+                        return RespValue::Error(
+                            "ERR Client names cannot contain spaces, newlines or special characters."
+                                .to_string(),
                         );
-                        RespValue::SimpleString("OK".to_string())
                     }
-                    _ => RespValue::Error("unknown argument".to_string()),
+                    let name = Some(name).filter(|name| !name.is_empty());
+                    settings.clients.lock().unwrap().set_name(state.id, name);
+                    RespValue::SimpleString("OK".to_string())
+                }
+                ("list", []) => {
+                    let clients = settings.clients.lock().unwrap();
+                    let list = clients
+                        .iter()
+                        .map(|(id, client)| {
+                            format!(
+                                "id={} addr={} name={}\n",
+                                id,
+                                client.addr,
+                                client.name.as_deref().unwrap_or_default()
+                            )
+                        })
+                        .collect::<String>();
+                    RespValue::text(list)
+                }
+                ("id" | "getname" | "setname" | "list", _) => {
+                    RespValue::Error("ERR wrong number of arguments".to_string())
+                }
+                _ => RespValue::Error("ERR unknown subcommand".to_string()),
+            }
+        }
+        "echo" => args.first().unwrap().clone(),
+        "select" => match args.as_slice() {
+            [index] => match db_index(index, &databases) {
+                Ok(index) => {
+                    state.db = index;
+                    RespValue::SimpleString("OK".to_string())
+                }
+                Err(e) => e,
+            },
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "set" => match args.as_slice() {
+            [key, RespValue::BulkString(Some(value)), options @ ..] => {
+                let key = arg_string(key).unwrap();
+                let value = Value::String(Arc::new(value.clone()));
+                let mut deadline = None;
+                let mut nx = false;
+                let mut xx = false;
+                let mut keepttl = false;
+                let mut get = false;
+
+                let mut options = options.iter();
+                while let Some(option) = options.next() {
+                    let option = arg_string(option).unwrap_or_default().to_ascii_lowercase();
+                    match option.as_str() {
+                        "ex" | "px" | "exat" | "pxat" => {
+                            let millis = match options.next().and_then(arg_int) {
+                                Some(amount) if amount > 0 => match option.as_str() {
+                                    "ex" | "exat" => (amount as u64).checked_mul(1000),
+                                    _ => Some(amount as u64),
+                                },
+                                _ => None,
+                            };
+                            // Deadlines are monotonic instants, so translate
+                            // wall-clock times into a delay from now.
+                            let millis = match option.as_str() {
+                                "exat" | "pxat" => {
+                                    millis.map(|at| at.saturating_sub(rdb::unix_millis()))
+                                }
+                                _ => millis,
+                            };
+                            deadline = match millis.and_then(deadline_in) {
+                                Some(deadline) => Some(deadline),
+                                None => {
+                                    return RespValue::Error(
+                                        "ERR invalid expire time in 'set' command".to_string(),
+                                    )
+                                }
+                            };
+                        }
+                        "nx" => nx = true,
+                        "xx" => xx = true,
+                        "keepttl" => keepttl = true,
+                        "get" => get = true,
+                        _ => return RespValue::Error("ERR syntax error".to_string()),
+                    }
+                }
+
+                if (nx && xx) || (keepttl && deadline.is_some()) {
+                    return RespValue::Error("ERR syntax error".to_string());
+                }
+
+                let mut storage = db.write(&key).await;
+                let (exists, previous) = match storage.lookup(&key) {
+                    Some(Value::String(previous)) => (true, Some(previous.clone())),
+                    Some(_) if get => return RespValue::Error(WRONGTYPE.to_string()),
+                    Some(_) => (true, None),
+                    None => (false, None),
+                };
+                let reply = match get {
+                    true => {
+                        previous.map_or(RespValue::BulkString(None), RespValue::SharedBulkString)
+                    }
+                    false => RespValue::SimpleString("OK".to_string()),
+                };
+
+                if (nx && exists) || (xx && !exists) {
+                    return match get {
+                        true => reply,
+                        false => RespValue::BulkString(None),
+                    };
+                }
+
+                match storage.get_mut(&key) {
+                    Some(data) if keepttl => data.value = value,
+                    _ => storage.set(key, value, deadline),
                 }
+                reply
             }
-            _ => RespValue::Error("wrong number of arguments".to_string()),
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "setnx" => match args.as_slice() {
+            [key, RespValue::BulkString(Some(value))] => {
+                let key = arg_string(key).unwrap();
+                let mut storage = db.write(&key).await;
+                if storage.exists(&key) {
+                    return RespValue::Integer(0);
+                }
+                storage.set(key, Value::String(Arc::new(value.clone())), None);
+                RespValue::Integer(1)
+            }
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "setex" | "psetex" => match args.as_slice() {
+            [key, timeout, RespValue::BulkString(Some(value))] => {
+                let millis = match arg_int(timeout) {
+                    Some(amount) if amount > 0 => match command.as_str() {
+                        "setex" => (amount as u64).checked_mul(1000),
+                        _ => Some(amount as u64),
+                    },
+                    _ => None,
+                };
+                let Some(deadline) = millis.and_then(deadline_in) else {
+                    return RespValue::Error(format!(
+                        "ERR invalid expire time in '{}' command",
+                        command
+                    ));
+                };
+
+                let key = arg_string(key).unwrap();
+                let value = Value::String(Arc::new(value.clone()));
+                db.write(&key).await.set(key, value, Some(deadline));
+                RespValue::SimpleString("OK".to_string())
+            }
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
         },
         "get" => {
-            let mut storage = storage.write().await;
-            match storage.get(&String::from_utf8(args[0].to_bytes().clone()).unwrap()) {
-                Some(value) => value.clone(),
-                None => RespValue::BulkString(None),
-            }
-        }
-        "info" => match args.as_slice() {
-            [] => RespValue::BulkString(Some(b"# Server\nversion:0.0.1\n".to_vec())),
-            [RespValue::BulkString(Some(key))] => match String::from_utf8(key.clone())
-                .unwrap()
-                .to_ascii_lowercase()
-                .as_str()
+            let key = arg_string(&args[0]).unwrap();
             {
-                "replication" => {
-                    let role = match settings.replicaof {
-                        Some(_) => "slave",
-                        None => "master",
+                let storage = db.read(&key).await;
+                if !storage.is_expired(&key) {
+                    let value = storage.peek(&key);
+                    settings.stats.lookup(value.is_some());
+                    return match value {
+                        Some(Value::String(value)) => RespValue::SharedBulkString(value.clone()),
+                        Some(_) => RespValue::Error(WRONGTYPE.to_string()),
+                        None => RespValue::BulkString(None),
                     };
+                }
+            }
+            // Only evicting an expired key needs the write lock.
+            db.write(&key).await.remove_if_expired(&key);
+            settings.stats.lookup(false);
+            RespValue::BulkString(None)
+        }
+        "strlen" => match args.as_slice() {
+            [key] => {
+                let key = arg_string(key).unwrap();
+                let mut storage = db.write(&key).await;
+                match storage.lookup(&key).map(expect_string) {
+                    Some(Ok(value)) => RespValue::Integer(value.len() as i64),
+                    Some(Err(e)) => e,
+                    None => RespValue::Integer(0),
+                }
+            }
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "getrange" => match args.as_slice() {
+            [key, start, end] => {
+                let (Some(start), Some(end)) = (arg_int(start), arg_int(end)) else {
+                    return RespValue::Error(
+                        "ERR value is not an integer or out of range".to_string(),
+                    );
+                };
+
+                let key = arg_string(key).unwrap();
+                let mut storage = db.write(&key).await;
+                let value = match storage.lookup(&key).map(expect_string) {
+                    Some(Ok(value)) => value,
+                    Some(Err(e)) => return e,
+                    None => &[],
+                };
 
-                    RespValue::BulkString(Some(Vec::from(
-                        format!("# Replication\nrole:{}\nmaster_replid:8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb\nmaster_repl_offset:0\n", role).as_bytes(),
-                    )))
+                let len = value.len() as i64;
+                let start = if start < 0 { start + len } else { start }.max(0);
+                let end = if end < 0 { end + len } else { end }.min(len - 1);
+                if start > end {
+                    return RespValue::BulkString(Some(vec![]));
                 }
-                _ => RespValue::Error("unknown argument".to_string()),
-            },
-            _ => RespValue::Error("wrong number of arguments".to_string()),
+
+                RespValue::BulkString(Some(value[start as usize..=end as usize].to_vec()))
+            }
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
         },
-        "replconf" => RespValue::BulkString(Some(b"OK".to_vec())),
-        "psync" => RespValue::BulkString(Some(
-            b"+FULLRESYNC 8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb 0\n".to_vec(),
-        )),
-        _ => RespValue::Error("unknown command".to_string()),
+        "setrange" => match args.as_slice() {
+            [key, offset, RespValue::BulkString(Some(value))] => {
+                let offset = match arg_int(offset) {
+                    Some(offset) if offset >= 0 => offset as usize,
+                    _ => return RespValue::Error("ERR offset is out of range".to_string()),
+                };
+                let end = match offset.checked_add(value.len()) {
+                    Some(end) if end <= MAX_STRING_LEN || value.is_empty() => end,
+                    _ => {
+                        return RespValue::Error(
+                            "ERR string exceeds maximum allowed size (proto-max-bulk-len)"
+                                .to_string(),
+                        )
+                    }
+                };
+                let key = arg_string(key).unwrap();
+
+                let mut storage = db.write(&key).await;
+                if !storage.exists(&key) {
+                    if value.is_empty() {
+                        return RespValue::Integer(0);
+                    }
+                    storage.set(key.clone(), Value::String(Arc::default()), None);
+                }
+
+                let existing = match storage
+                    .get_mut(&key)
+                    .map(|data| expect_string_mut(&mut data.value))
+                {
+                    Some(Ok(existing)) => existing,
+                    Some(Err(e)) => return e,
+                    None => unreachable!("the key was created above"),
+                };
+
+                if !value.is_empty() {
+                    if existing.len() < end {
+                        existing.resize(end, 0);
+                    }
+                    existing[offset..end].copy_from_slice(value);
+                }
+                RespValue::Integer(existing.len() as i64)
+            }
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "getdel" => match args.as_slice() {
+            [key] => {
+                let key = arg_string(key).unwrap();
+                let mut storage = db.write(&key).await;
+                if let Some(Err(e)) = storage.lookup(&key).map(expect_string) {
+                    return e;
+                }
+                match storage.take(&key) {
+                    Some(Value::String(value)) => RespValue::SharedBulkString(value),
+                    _ => RespValue::BulkString(None),
+                }
+            }
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "getset" => match args.as_slice() {
+            [key, RespValue::BulkString(Some(value))] => {
+                let key = arg_string(key).unwrap();
+                let mut storage = db.write(&key).await;
+                let previous = match storage.lookup(&key) {
+                    Some(Value::String(previous)) => RespValue::SharedBulkString(previous.clone()),
+                    Some(_) => return RespValue::Error(WRONGTYPE.to_string()),
+                    None => RespValue::BulkString(None),
+                };
+                storage.set(key, Value::String(Arc::new(value.clone())), None);
+                previous
+            }
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "append" => match args.as_slice() {
+            [key, RespValue::BulkString(Some(value))] => {
+                let key = arg_string(key).unwrap();
+                let mut storage = db.write(&key).await;
+                match storage
+                    .get_mut(&key)
+                    .map(|data| expect_string_mut(&mut data.value))
+                {
+                    Some(Ok(existing)) => {
+                        existing.extend_from_slice(value);
+                        RespValue::Integer(existing.len() as i64)
+                    }
+                    Some(Err(e)) => e,
+                    None => {
+                        let len = value.len();
+                        storage.set(key, Value::String(Arc::new(value.clone())), None);
+                        RespValue::Integer(len as i64)
+                    }
+                }
+            }
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "setbit" => match args.as_slice() {
+            [key, offset, bit] => {
+                let Some(offset) =
+                    arg_int(offset).filter(|offset| (0..bitmap::MAX_OFFSET).contains(offset))
+                else {
+                    return RespValue::Error(
+                        "ERR bit offset is not an integer or out of range".to_string(),
+                    );
+                };
+                let bit = match arg_int(bit) {
+                    Some(bit @ (0 | 1)) => bit == 1,
+                    _ => {
+                        return RespValue::Error(
+                            "ERR bit is not an integer or out of range".to_string(),
+                        )
+                    }
+                };
+                let key = arg_string(key).unwrap();
+
+                let mut storage = db.write(&key).await;
+                if !storage.exists(&key) {
+                    storage.set(key.clone(), Value::String(Arc::default()), None);
+                }
+                match storage
+                    .get_mut(&key)
+                    .map(|data| expect_string_mut(&mut data.value))
+                {
+                    Some(Ok(existing)) => {
+                        RespValue::Integer(bitmap::set_bit(existing, offset as usize, bit) as i64)
+                    }
+                    Some(Err(e)) => e,
+                    None => unreachable!("the key was created above"),
+                }
+            }
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "getbit" => match args.as_slice() {
+            [key, offset] => {
+                let Some(offset) =
+                    arg_int(offset).filter(|offset| (0..bitmap::MAX_OFFSET).contains(offset))
+                else {
+                    return RespValue::Error(
+                        "ERR bit offset is not an integer or out of range".to_string(),
+                    );
+                };
+                let key = arg_string(key).unwrap();
+
+                let mut storage = db.write(&key).await;
+                match storage.lookup(&key).map(expect_string) {
+                    Some(Ok(bits)) => {
+                        RespValue::Integer(bitmap::get_bit(bits, offset as usize) as i64)
+                    }
+                    Some(Err(e)) => e,
+                    None => RespValue::Integer(0),
+                }
+            }
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "bitcount" => match args.as_slice() {
+            [key, range @ ..] => {
+                let (range, unit) = match range {
+                    [] => (None, "byte".to_string()),
+                    [start, end, unit @ ..] if unit.len() <= 1 => {
+                        let (Some(start), Some(end)) = (arg_int(start), arg_int(end)) else {
+                            return RespValue::Error(
+                                "ERR value is not an integer or out of range".to_string(),
+                            );
+                        };
+                        let unit = unit
+                            .first()
+                            .and_then(arg_string)
+                            .unwrap_or("byte".to_string());
+                        (Some((start, end)), unit.to_ascii_lowercase())
+                    }
+                    _ => return RespValue::Error("ERR syntax error".to_string()),
+                };
+                let bytes_per_unit = match unit.as_str() {
+                    "byte" => 1,
+                    "bit" => 8,
+                    _ => return RespValue::Error("ERR syntax error".to_string()),
+                };
+                let key = arg_string(key).unwrap();
+
+                let mut storage = db.write(&key).await;
+                let bits = match storage.lookup(&key).map(expect_string) {
+                    Some(Ok(bits)) => bits,
+                    Some(Err(e)) => return e,
+                    None => return RespValue::Integer(0),
+                };
+                let Some((start, end)) = range else {
+                    return RespValue::Integer(bitmap::popcount(bits) as i64);
+                };
+
+                let len = (bits.len() * bytes_per_unit) as i64;
+                let start = if start < 0 { start + len } else { start }.max(0);
+                let end = if end < 0 { end + len } else { end }.min(len - 1);
+                if start > end {
+                    return RespValue::Integer(0);
+                }
+                let (start, end) = match bytes_per_unit {
+                    1 => (start as usize * 8, end as usize * 8 + 7),
+                    _ => (start as usize, end as usize),
+                };
+                RespValue::Integer(bitmap::count(bits, start, end) as i64)
+            }
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "mget" => match args.as_slice() {
+            [] => RespValue::Error("ERR wrong number of arguments".to_string()),
+            keys => {
+                let keys = keys.iter().filter_map(arg_string).collect::<Vec<_>>();
+                let shards = db.read_keys(keys.iter().map(String::as_str)).await;
+                RespValue::Array(
+                    keys.iter()
+                        .map(|key| match shards.get(key).peek(key) {
+                            Some(Value::String(value)) => {
+                                RespValue::SharedBulkString(value.clone())
+                            }
+                            _ => RespValue::BulkString(None),
+                        })
+                        .collect(),
+                )
+            }
+        },
+        "mset" => match args.as_slice() {
+            pairs if !pairs.is_empty() && pairs.len() % 2 == 0 => {
+                let pairs = pairs
+                    .chunks(2)
+                    .filter_map(|pair| match &pair[1] {
+                        RespValue::BulkString(Some(value)) => {
+                            Some((arg_string(&pair[0]).unwrap(), value.clone()))
+                        }
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>();
+                let mut shards = db
+                    .write_keys(pairs.iter().map(|(key, _)| key.as_str()))
+                    .await;
+                for (key, value) in pairs {
+                    shards
+                        .get(&key)
+                        .set(key, Value::String(Arc::new(value)), None);
+                }
+                RespValue::SimpleString("OK".to_string())
+            }
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "info" => {
+            let mut sections = vec![];
+            for section in args.iter().filter_map(arg_string) {
+                let section = section.to_ascii_lowercase();
+                match section.as_str() {
+                    "all" | "everything" | "default" => {
+                        sections.extend(INFO_SECTIONS.map(str::to_string))
+                    }
+                    _ => sections.push(section),
+                }
+            }
+            if args.is_empty() {
+                sections.extend(INFO_SECTIONS.map(str::to_string));
+            }
+
+            let mut info = vec![];
+            for (i, section) in sections.iter().enumerate() {
+                // Sections asked for more than once are only reported once.
+                if !sections[..i].contains(section) {
+                    info.extend(info_section(section, &databases, &settings, &replication).await);
+                }
+            }
+            RespValue::text(info.join("\n"))
+        }
+        "del" => match args.as_slice() {
+            [] => RespValue::Error("ERR wrong number of arguments".to_string()),
+            keys => {
+                let keys = keys.iter().filter_map(arg_string).collect::<Vec<_>>();
+                let mut shards = db.write_keys(keys.iter().map(String::as_str)).await;
+                let deleted = keys.iter().filter(|key| shards.get(key).del(key)).count();
+                RespValue::Integer(deleted as i64)
+            }
+        },
+        "unlink" => match args.as_slice() {
+            [] => RespValue::Error("ERR wrong number of arguments".to_string()),
+            keys => {
+                let keys = keys.iter().filter_map(arg_string).collect::<Vec<_>>();
+                let mut shards = db.write_keys(keys.iter().map(String::as_str)).await;
+                let mut unlinked = 0;
+                let mut large = vec![];
+                for key in &keys {
+                    if let Some(value) = shards.get(key).take(key) {
+                        unlinked += 1;
+                        if value.elements() > LAZYFREE_THRESHOLD {
+                            large.push(value);
+                        }
+                    }
+                }
+                drop(shards);
+
+                if !large.is_empty() {
+                    tokio::task::spawn_blocking(move || drop(large));
+                }
+                RespValue::Integer(unlinked)
+            }
+        },
+        "exists" | "touch" => match args.as_slice() {
+            [] => RespValue::Error("ERR wrong number of arguments".to_string()),
+            keys => {
+                let keys = keys.iter().filter_map(arg_string).collect::<Vec<_>>();
+                let mut shards = db.write_keys(keys.iter().map(String::as_str)).await;
+                // Unlike EXISTS, TOUCH counts as an access to the keys.
+                let existing = keys
+                    .iter()
+                    .filter(|key| match command.as_str() {
+                        "touch" => shards.get(key).lookup(key).is_some(),
+                        _ => shards.get(key).exists(key),
+                    })
+                    .count();
+                RespValue::Integer(existing as i64)
+            }
+        },
+        "incr" | "decr" | "incrby" | "decrby" => {
+            let (key, delta) = match (command.as_str(), args.as_slice()) {
+                ("incr", [key]) => (key, Some(1)),
+                ("decr", [key]) => (key, Some(-1)),
+                ("incrby", [key, delta]) => (key, arg_int(delta)),
+                ("decrby", [key, delta]) => (key, arg_int(delta).and_then(i64::checked_neg)),
+                _ => return RespValue::Error("ERR wrong number of arguments".to_string()),
+            };
+            let Some(delta) = delta else {
+                return RespValue::Error("ERR value is not an integer or out of range".to_string());
+            };
+            let key = arg_string(key).unwrap();
+
+            let mut storage = db.write(&key).await;
+            let current = match stored_number::<i64>(&mut storage, &key) {
+                Ok(current) => current,
+                Err(e) => return e,
+            };
+            match current.and_then(|current| current.checked_add(delta)) {
+                Some(result) => {
+                    store_number(&mut storage, key, result.to_string());
+                    RespValue::Integer(result)
+                }
+                None => RespValue::Error("ERR value is not an integer or out of range".to_string()),
+            }
+        }
+        "incrbyfloat" => match args.as_slice() {
+            [key, delta] => {
+                let Some(delta) = arg_float(delta).filter(|delta| delta.is_finite()) else {
+                    return RespValue::Error("ERR value is not a valid float".to_string());
+                };
+                let key = arg_string(key).unwrap();
+
+                let mut storage = db.write(&key).await;
+                let current = match stored_number::<f64>(&mut storage, &key) {
+                    Ok(current) => current.filter(|current| current.is_finite()),
+                    Err(e) => return e,
+                };
+                let Some(current) = current else {
+                    return RespValue::Error("ERR value is not a valid float".to_string());
+                };
+
+                let result = current + delta;
+                if !result.is_finite() {
+                    return RespValue::Error(
+                        "ERR increment would produce NaN or Infinity".to_string(),
+                    );
+                }
+                // Display formats whole floats without a trailing `.0`.
+                let result = result.to_string();
+                store_number(&mut storage, key, result.clone());
+                RespValue::BulkString(Some(result.into_bytes()))
+            }
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "lpush" | "rpush" => match args.as_slice() {
+            [key, elements @ ..] if !elements.is_empty() => {
+                let key = arg_string(key).unwrap();
+                let mut storage = db.write(&key).await;
+                if !storage.exists(&key) {
+                    storage.set(key.clone(), Value::List(VecDeque::new()), None);
+                }
+
+                let list = match storage.get_mut(&key) {
+                    Some(Data {
+                        value: Value::List(list),
+                        ..
+                    }) => list,
+                    _ => return RespValue::Error(WRONGTYPE.to_string()),
+                };
+
+                for element in elements {
+                    let RespValue::BulkString(Some(element)) = element else {
+                        continue;
+                    };
+                    match command.as_str() {
+                        "lpush" => list.push_front(element.clone()),
+                        _ => list.push_back(element.clone()),
+                    }
+                }
+                let len = list.len();
+                storage.notify_waiters(&key);
+                RespValue::Integer(len as i64)
+            }
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "lpop" | "rpop" => match args.as_slice() {
+            [key, count @ ..] if count.len() <= 1 => {
+                let count = match count.first().map(arg_int) {
+                    Some(Some(count)) if count >= 0 => Some(count as usize),
+                    Some(_) => {
+                        return RespValue::Error(
+                            "ERR value is out of range, must be positive".to_string(),
+                        )
+                    }
+                    None => None,
+                };
+                let key = arg_string(key).unwrap();
+
+                let mut storage = db.write(&key).await;
+                let list = match storage.get_mut(&key) {
+                    Some(Data {
+                        value: Value::List(list),
+                        ..
+                    }) => list,
+                    Some(_) => return RespValue::Error(WRONGTYPE.to_string()),
+                    None if count.is_some() => return RespValue::NullArray,
+                    None => return RespValue::BulkString(None),
+                };
+
+                let mut popped = vec![];
+                for _ in 0..count.unwrap_or(1) {
+                    let element = match command.as_str() {
+                        "lpop" => list.pop_front(),
+                        _ => list.pop_back(),
+                    };
+                    match element {
+                        Some(element) => popped.push(RespValue::BulkString(Some(element))),
+                        None => break,
+                    }
+                }
+                if list.is_empty() {
+                    storage.del(&key);
+                }
+
+                match count {
+                    Some(_) => RespValue::Array(popped),
+                    None => popped.pop().unwrap_or(RespValue::BulkString(None)),
+                }
+            }
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "blpop" => match args.as_slice() {
+            [keys @ .., timeout] if !keys.is_empty() => {
+                let timeout = match arg_string(timeout).and_then(|t| t.parse::<f64>().ok()) {
+                    Some(timeout) if timeout < 0.0 => {
+                        return RespValue::Error("ERR timeout is negative".to_string())
+                    }
+                    Some(timeout) if timeout.is_finite() => timeout,
+                    _ => {
+                        return RespValue::Error(
+                            "ERR timeout is not a float or out of range".to_string(),
+                        )
+                    }
+                };
+                let deadline = match timeout {
+                    0.0 => None,
+                    timeout => match Duration::try_from_secs_f64(timeout)
+                        .ok()
+                        .and_then(|timeout| Instant::now().checked_add(timeout))
+                    {
+                        Some(deadline) => Some(deadline),
+                        None => return RespValue::Error("ERR timeout is out of range".to_string()),
+                    },
+                };
+                let keys = keys.iter().filter_map(arg_string).collect::<Vec<_>>();
+
+                // Inside a transaction, BLPOP does not wait like LPOP.
+                let mut timed_out = state.executing;
+                loop {
+                    let shared = match state.executing {
+                        true => None,
+                        false => Some(settings.transactions.read().await),
+                    };
+                    let mut shards = db.write_keys(keys.iter().map(String::as_str)).await;
+                    for key in &keys {
+                        let storage = shards.get(key);
+                        let list = match storage.get_mut(key) {
+                            Some(Data {
+                                value: Value::List(list),
+                                ..
+                            }) => list,
+                            Some(_) => return RespValue::Error(WRONGTYPE.to_string()),
+                            None => continue,
+                        };
+                        if let Some(element) = list.pop_front() {
+                            if list.is_empty() {
+                                storage.del(key);
+                            }
+                            return RespValue::Array(vec![
+                                RespValue::BulkString(Some(key.clone().into_bytes())),
+                                RespValue::BulkString(Some(element)),
+                            ]);
+                        }
+                    }
+
+                    if timed_out {
+                        return RespValue::NullArray;
+                    }
+
+                    let waiters = keys
+                        .iter()
+                        .map(|key| shards.get(key).waiter(key))
+                        .collect::<Vec<_>>();
+                    let pushed = any_notified(&waiters);
+                    drop(shards);
+                    drop(shared);
+
+                    match deadline {
+                        Some(deadline) => {
+                            timed_out = tokio::time::timeout_at(deadline, pushed).await.is_err()
+                        }
+                        None => pushed.await,
+                    }
+                }
+            }
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "llen" => match args.as_slice() {
+            [key] => {
+                let key = arg_string(key).unwrap();
+                let mut storage = db.write(&key).await;
+                match storage.lookup(&key) {
+                    Some(Value::List(list)) => RespValue::Integer(list.len() as i64),
+                    Some(_) => RespValue::Error(WRONGTYPE.to_string()),
+                    None => RespValue::Integer(0),
+                }
+            }
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "lrange" => match args.as_slice() {
+            [key, start, stop] => {
+                let (Some(start), Some(stop)) = (arg_int(start), arg_int(stop)) else {
+                    return RespValue::Error(
+                        "ERR value is not an integer or out of range".to_string(),
+                    );
+                };
+
+                let key = arg_string(key).unwrap();
+                let mut storage = db.write(&key).await;
+                let list = match storage.lookup(&key) {
+                    Some(Value::List(list)) => list,
+                    Some(_) => return RespValue::Error(WRONGTYPE.to_string()),
+                    None => return RespValue::Array(vec![]),
+                };
+
+                let len = list.len() as i64;
+                let start = if start < 0 { start + len } else { start }.max(0);
+                let stop = if stop < 0 { stop + len } else { stop }.min(len - 1);
+                if start > stop {
+                    return RespValue::Array(vec![]);
+                }
+
+                RespValue::Array(
+                    list.range(start as usize..=stop as usize)
+                        .map(|element| RespValue::BulkString(Some(element.clone())))
+                        .collect(),
+                )
+            }
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "hset" => match args.as_slice() {
+            [key, pairs @ ..] if !pairs.is_empty() && pairs.len() % 2 == 0 => {
+                let key = arg_string(key).unwrap();
+                let mut storage = db.write(&key).await;
+                if !storage.exists(&key) {
+                    storage.set(key.clone(), Value::Hash(HashMap::new()), None);
+                }
+
+                let hash = match storage.get_mut(&key) {
+                    Some(Data {
+                        value: Value::Hash(hash),
+                        ..
+                    }) => hash,
+                    _ => return RespValue::Error(WRONGTYPE.to_string()),
+                };
+
+                let mut created = 0;
+                for pair in pairs.chunks(2) {
+                    let [RespValue::BulkString(Some(field)), RespValue::BulkString(Some(value))] =
+                        pair
+                    else {
+                        continue;
+                    };
+                    if hash.insert(field.clone(), value.clone()).is_none() {
+                        created += 1;
+                    }
+                }
+                RespValue::Integer(created)
+            }
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "hget" => match args.as_slice() {
+            [key, RespValue::BulkString(Some(field))] => {
+                let key = arg_string(key).unwrap();
+                let mut storage = db.write(&key).await;
+                match storage.lookup(&key) {
+                    Some(Value::Hash(hash)) => RespValue::BulkString(hash.get(field).cloned()),
+                    Some(_) => RespValue::Error(WRONGTYPE.to_string()),
+                    None => RespValue::BulkString(None),
+                }
+            }
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "hgetall" => match args.as_slice() {
+            [key] => {
+                let key = arg_string(key).unwrap();
+                let mut storage = db.write(&key).await;
+                match storage.lookup(&key) {
+                    Some(Value::Hash(hash)) => RespValue::Map(
+                        hash.iter()
+                            .map(|(field, value)| {
+                                (
+                                    RespValue::BulkString(Some(field.clone())),
+                                    RespValue::BulkString(Some(value.clone())),
+                                )
+                            })
+                            .collect(),
+                    ),
+                    Some(_) => RespValue::Error(WRONGTYPE.to_string()),
+                    None => RespValue::Map(vec![]),
+                }
+            }
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "hdel" => match args.as_slice() {
+            [key, fields @ ..] if !fields.is_empty() => {
+                let key = arg_string(key).unwrap();
+                let mut storage = db.write(&key).await;
+                let hash = match storage.get_mut(&key) {
+                    Some(Data {
+                        value: Value::Hash(hash),
+                        ..
+                    }) => hash,
+                    Some(_) => return RespValue::Error(WRONGTYPE.to_string()),
+                    None => return RespValue::Integer(0),
+                };
+
+                let removed = fields
+                    .iter()
+                    .filter(|field| match field {
+                        RespValue::BulkString(Some(field)) => hash.remove(field).is_some(),
+                        _ => false,
+                    })
+                    .count();
+                if hash.is_empty() {
+                    storage.del(&key);
+                }
+                RespValue::Integer(removed as i64)
+            }
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "hlen" => match args.as_slice() {
+            [key] => {
+                let key = arg_string(key).unwrap();
+                let mut storage = db.write(&key).await;
+                match storage.lookup(&key) {
+                    Some(Value::Hash(hash)) => RespValue::Integer(hash.len() as i64),
+                    Some(_) => RespValue::Error(WRONGTYPE.to_string()),
+                    None => RespValue::Integer(0),
+                }
+            }
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "sadd" => match args.as_slice() {
+            [key, members @ ..] if !members.is_empty() => {
+                let key = arg_string(key).unwrap();
+                let mut storage = db.write(&key).await;
+                if !storage.exists(&key) {
+                    storage.set(key.clone(), Value::Set(HashSet::new()), None);
+                }
+
+                let set = match storage.get_mut(&key) {
+                    Some(Data {
+                        value: Value::Set(set),
+                        ..
+                    }) => set,
+                    _ => return RespValue::Error(WRONGTYPE.to_string()),
+                };
+
+                let added = members
+                    .iter()
+                    .filter(|member| match member {
+                        RespValue::BulkString(Some(member)) => set.insert(member.clone()),
+                        _ => false,
+                    })
+                    .count();
+                RespValue::Integer(added as i64)
+            }
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "srem" => match args.as_slice() {
+            [key, members @ ..] if !members.is_empty() => {
+                let key = arg_string(key).unwrap();
+                let mut storage = db.write(&key).await;
+                let set = match storage.get_mut(&key) {
+                    Some(Data {
+                        value: Value::Set(set),
+                        ..
+                    }) => set,
+                    Some(_) => return RespValue::Error(WRONGTYPE.to_string()),
+                    None => return RespValue::Integer(0),
+                };
+
+                let removed = members
+                    .iter()
+                    .filter(|member| match member {
+                        RespValue::BulkString(Some(member)) => set.remove(member),
+                        _ => false,
+                    })
+                    .count();
+                if set.is_empty() {
+                    storage.del(&key);
+                }
+                RespValue::Integer(removed as i64)
+            }
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "smembers" => match args.as_slice() {
+            [key] => {
+                let key = arg_string(key).unwrap();
+                let mut storage = db.write(&key).await;
+                match storage.lookup(&key) {
+                    Some(Value::Set(set)) => RespValue::Set(
+                        set.iter()
+                            .map(|member| RespValue::BulkString(Some(member.clone())))
+                            .collect(),
+                    ),
+                    Some(_) => RespValue::Error(WRONGTYPE.to_string()),
+                    None => RespValue::Set(vec![]),
+                }
+            }
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "sismember" => match args.as_slice() {
+            [key, RespValue::BulkString(Some(member))] => {
+                let key = arg_string(key).unwrap();
+                let mut storage = db.write(&key).await;
+                match storage.lookup(&key) {
+                    Some(Value::Set(set)) => RespValue::Integer(set.contains(member) as i64),
+                    Some(_) => RespValue::Error(WRONGTYPE.to_string()),
+                    None => RespValue::Integer(0),
+                }
+            }
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "zadd" => match args.as_slice() {
+            [key, pairs @ ..] if !pairs.is_empty() && pairs.len() % 2 == 0 => {
+                let mut members = vec![];
+                for pair in pairs.chunks(2) {
+                    let (Some(score), RespValue::BulkString(Some(member))) =
+                        (arg_float(&pair[0]), &pair[1])
+                    else {
+                        return RespValue::Error("ERR value is not a valid float".to_string());
+                    };
+                    members.push((member.clone(), score));
+                }
+                let key = arg_string(key).unwrap();
+
+                let mut storage = db.write(&key).await;
+                if !storage.exists(&key) {
+                    storage.set(key.clone(), Value::SortedSet(SortedSet::new()), None);
+                }
+
+                let zset = match storage.get_mut(&key) {
+                    Some(Data {
+                        value: Value::SortedSet(zset),
+                        ..
+                    }) => zset,
+                    _ => return RespValue::Error(WRONGTYPE.to_string()),
+                };
+
+                let added = members
+                    .into_iter()
+                    .filter(|(member, score)| zset.insert(member.clone(), *score))
+                    .count();
+                RespValue::Integer(added as i64)
+            }
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "zrange" => match args.as_slice() {
+            [key, start, stop, options @ ..] if options.len() <= 1 => {
+                let withscores = match options.first().and_then(arg_string) {
+                    Some(option) if option.eq_ignore_ascii_case("withscores") => true,
+                    Some(_) => return RespValue::Error("ERR syntax error".to_string()),
+                    None => false,
+                };
+                let (Some(start), Some(stop)) = (arg_int(start), arg_int(stop)) else {
+                    return RespValue::Error(
+                        "ERR value is not an integer or out of range".to_string(),
+                    );
+                };
+
+                let key = arg_string(key).unwrap();
+                let mut storage = db.write(&key).await;
+                let zset = match storage.lookup(&key) {
+                    Some(Value::SortedSet(zset)) => zset,
+                    Some(_) => return RespValue::Error(WRONGTYPE.to_string()),
+                    None => return RespValue::Array(vec![]),
+                };
+
+                let len = zset.len() as i64;
+                let start = if start < 0 { start + len } else { start }.max(0);
+                let stop = if stop < 0 { stop + len } else { stop }.min(len - 1);
+                if start > stop {
+                    return RespValue::Array(vec![]);
+                }
+
+                let members = zset
+                    .iter()
+                    .skip(start as usize)
+                    .take((stop - start + 1) as usize);
+                RespValue::Array(match (withscores, state.protocol) {
+                    (false, _) => members
+                        .map(|(member, _)| RespValue::BulkString(Some(member.to_vec())))
+                        .collect(),
+                    (true, 3) => members
+                        .map(|(member, score)| {
+                            RespValue::Array(vec![
+                                RespValue::BulkString(Some(member.to_vec())),
+                                RespValue::Double(score),
+                            ])
+                        })
+                        .collect(),
+                    (true, _) => members
+                        .flat_map(|(member, score)| {
+                            [
+                                RespValue::BulkString(Some(member.to_vec())),
+                                RespValue::Double(score),
+                            ]
+                        })
+                        .collect(),
+                })
+            }
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "zscore" | "zrank" => match args.as_slice() {
+            [key, RespValue::BulkString(Some(member))] => {
+                let key = arg_string(key).unwrap();
+                let mut storage = db.write(&key).await;
+                let zset = match storage.lookup(&key) {
+                    Some(Value::SortedSet(zset)) => zset,
+                    Some(_) => return RespValue::Error(WRONGTYPE.to_string()),
+                    None => return RespValue::Null,
+                };
+
+                let result = match command.as_str() {
+                    "zscore" => zset.score(member).map(RespValue::Double),
+                    _ => zset
+                        .rank(member)
+                        .map(|rank| RespValue::Integer(rank as i64)),
+                };
+                result.unwrap_or(RespValue::Null)
+            }
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "xadd" => match args.as_slice() {
+            [key, id, pairs @ ..] if !pairs.is_empty() && pairs.len() % 2 == 0 => {
+                let key = arg_string(key).unwrap();
+                let mut fields = vec![];
+                for pair in pairs.chunks(2) {
+                    let [RespValue::BulkString(Some(field)), RespValue::BulkString(Some(value))] =
+                        pair
+                    else {
+                        return RespValue::Error("ERR syntax error".to_string());
+                    };
+                    fields.push((field.clone(), value.clone()));
+                }
+
+                let mut storage = db.write(&key).await;
+                if !storage.exists(&key) {
+                    storage.set(key.clone(), Value::Stream(Stream::new()), None);
+                }
+
+                let stream = match storage.get_mut(&key) {
+                    Some(Data {
+                        value: Value::Stream(stream),
+                        ..
+                    }) => stream,
+                    _ => return RespValue::Error(WRONGTYPE.to_string()),
+                };
+
+                let id =
+                    match stream.next_id(&arg_string(id).unwrap_or_default(), rdb::unix_millis()) {
+                        Ok(id) => id,
+                        Err(e) => {
+                            if stream.is_empty() {
+                                storage.del(&key);
+                            }
+                            return RespValue::Error(format!("ERR {}", e));
+                        }
+                    };
+                stream.add(id, fields);
+                storage.notify_waiters(&key);
+                RespValue::BulkString(Some(id.to_string().into_bytes()))
+            }
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "xrange" => match args.as_slice() {
+            [key, start, end, options @ ..] => {
+                let count = match options {
+                    [] => None,
+                    [option, count]
+                        if arg_string(option)
+                            .is_some_and(|option| option.eq_ignore_ascii_case("count")) =>
+                    {
+                        match arg_int(count) {
+                            Some(count) => Some(count.max(0) as usize),
+                            None => {
+                                return RespValue::Error(
+                                    "ERR value is not an integer or out of range".to_string(),
+                                )
+                            }
+                        }
+                    }
+                    _ => return RespValue::Error("ERR syntax error".to_string()),
+                };
+                let (Some(start), Some(end)) = (
+                    arg_string(start).and_then(|start| StreamId::parse_bound(&start, 0)),
+                    arg_string(end).and_then(|end| StreamId::parse_bound(&end, u64::MAX)),
+                ) else {
+                    return RespValue::Error(
+                        "ERR Invalid stream ID specified as stream command argument".to_string(),
+                    );
+                };
+
+                let key = arg_string(key).unwrap();
+                let mut storage = db.write(&key).await;
+                let stream = match storage.lookup(&key) {
+                    Some(Value::Stream(stream)) => stream,
+                    Some(_) => return RespValue::Error(WRONGTYPE.to_string()),
+                    None => return RespValue::Array(vec![]),
+                };
+
+                RespValue::Array(
+                    stream
+                        .range(start, end)
+                        .take(count.unwrap_or(usize::MAX))
+                        .map(|(id, fields)| stream_entry(id, fields))
+                        .collect(),
+                )
+            }
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "xread" => {
+            let mut count = None;
+            let mut deadline = None;
+            let mut block = false;
+            let mut args = args.iter();
+            let streams = loop {
+                let Some(option) = args.next() else {
+                    return RespValue::Error("ERR syntax error".to_string());
+                };
+                match arg_string(option)
+                    .unwrap_or_default()
+                    .to_ascii_lowercase()
+                    .as_str()
+                {
+                    "count" => match args.next().and_then(arg_int) {
+                        Some(n) => count = Some(n.max(0) as usize),
+                        None => {
+                            return RespValue::Error(
+                                "ERR value is not an integer or out of range".to_string(),
+                            )
+                        }
+                    },
+                    "block" => match args.next().and_then(arg_int) {
+                        Some(ms) if ms >= 0 => {
+                            // Inside a transaction, XREAD does not wait.
+                            block = !state.executing;
+                            if ms > 0 {
+                                deadline =
+                                    Instant::now().checked_add(Duration::from_millis(ms as u64));
+                                if deadline.is_none() {
+                                    return RespValue::Error(
+                                        "ERR timeout is out of range".to_string(),
+                                    );
+                                }
+                            }
+                        }
+                        _ => return RespValue::Error("ERR timeout is negative".to_string()),
+                    },
+                    "streams" => break args.as_slice(),
+                    _ => return RespValue::Error("ERR syntax error".to_string()),
+                }
+            };
+            if streams.is_empty() || streams.len() % 2 != 0 {
+                return RespValue::Error(
+                    "ERR Unbalanced 'xread' list of streams: for each stream key an ID or '$' must be specified."
+                        .to_string(),
+                );
+            }
+            let (keys, ids) = streams.split_at(streams.len() / 2);
+            let keys = keys.iter().filter_map(arg_string).collect::<Vec<_>>();
+
+            let mut after = vec![];
+            {
+                let _shared = match state.executing {
+                    true => None,
+                    false => Some(settings.transactions.read().await),
+                };
+                let mut shards = db.write_keys(keys.iter().map(String::as_str)).await;
+                for (key, id) in keys.iter().zip(ids) {
+                    let id = arg_string(id).unwrap_or_default();
+                    let id =
+                        match id.as_str() {
+                            "$" => match shards.get(key).lookup(key) {
+                                Some(Value::Stream(stream)) => stream.last_id(),
+                                _ => StreamId::default(),
+                            },
+                            id => match StreamId::parse_bound(id, 0) {
+                                Some(id) => id,
+                                None => return RespValue::Error(
+                                    "ERR Invalid stream ID specified as stream command argument"
+                                        .to_string(),
+                                ),
+                            },
+                        };
+                    after.push(id);
+                }
+            }
+
+            let mut timed_out = false;
+            loop {
+                let shared = match state.executing {
+                    true => None,
+                    false => Some(settings.transactions.read().await),
+                };
+                let mut shards = db.write_keys(keys.iter().map(String::as_str)).await;
+                let mut results = vec![];
+                for (key, id) in keys.iter().zip(&after) {
+                    let stream = match shards.get(key).lookup(key) {
+                        Some(Value::Stream(stream)) => stream,
+                        Some(_) => return RespValue::Error(WRONGTYPE.to_string()),
+                        None => continue,
+                    };
+                    let entries = stream
+                        .after(*id)
+                        .take(count.unwrap_or(usize::MAX))
+                        .map(|(id, fields)| stream_entry(id, fields))
+                        .collect::<Vec<_>>();
+                    if !entries.is_empty() {
+                        results.push(RespValue::Array(vec![
+                            RespValue::BulkString(Some(key.clone().into_bytes())),
+                            RespValue::Array(entries),
+                        ]));
+                    }
+                }
+
+                if !results.is_empty() {
+                    return RespValue::Array(results);
+                }
+                if !block || timed_out {
+                    return RespValue::NullArray;
+                }
+
+                let waiters = keys
+                    .iter()
+                    .map(|key| shards.get(key).waiter(key))
+                    .collect::<Vec<_>>();
+                let appended = any_notified(&waiters);
+                drop(shards);
+                drop(shared);
+
+                match deadline {
+                    Some(deadline) => {
+                        timed_out = tokio::time::timeout_at(deadline, appended).await.is_err()
+                    }
+                    None => appended.await,
+                }
+            }
+        }
+        "object" => {
+            let subcommand = args
+                .first()
+                .and_then(arg_string)
+                .unwrap_or_default()
+                .to_ascii_lowercase();
+            match (subcommand.as_str(), args.get(1..).unwrap_or_default()) {
+                ("encoding", [key]) => {
+                    let key = arg_string(key).unwrap();
+                    let mut storage = db.write(&key).await;
+                    match storage.entry(&key) {
+                        Some(data) => RespValue::BulkString(Some(data.value.encoding().into())),
+                        None => RespValue::BulkString(None),
+                    }
+                }
+                ("refcount", [key]) => {
+                    let key = arg_string(key).unwrap();
+                    let mut storage = db.write(&key).await;
+                    match storage.entry(&key) {
+                        Some(data) => RespValue::Integer(data.value.refcount()),
+                        None => RespValue::BulkString(None),
+                    }
+                }
+                ("idletime", [key]) => {
+                    let key = arg_string(key).unwrap();
+                    let mut storage = db.write(&key).await;
+                    match storage.entry(&key) {
+                        Some(data) => RespValue::Integer(data.accessed.idle().as_secs() as i64),
+                        None => RespValue::BulkString(None),
+                    }
+                }
+                // There is no LFU maxmemory policy to choose.
+                ("freq", [_]) => RespValue::Error(
+                    "ERR An LFU maxmemory policy is not selected, access frequency not tracked."
+                        .to_string(),
+                ),
+                ("encoding" | "refcount" | "idletime" | "freq", _) => {
+                    RespValue::Error("ERR wrong number of arguments".to_string())
+                }
+                _ => RespValue::Error("ERR unknown subcommand".to_string()),
+            }
+        }
+        "debug" => {
+            let subcommand = args
+                .first()
+                .and_then(arg_string)
+                .unwrap_or_default()
+                .to_ascii_lowercase();
+            match (subcommand.as_str(), args.get(1..).unwrap_or_default()) {
+                // Only this connection waits: neither the storage nor the
+                // transaction lock is held, see `execute`.
+                ("sleep", [seconds]) => {
+                    match arg_float(seconds)
+                        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+                    {
+                        Some(duration) => {
+                            tokio::time::sleep(duration).await;
+                            RespValue::SimpleString("OK".to_string())
+                        }
+                        None => RespValue::Error("ERR value is not a valid float".to_string()),
+                    }
+                }
+                ("set-active-expire", [enabled]) => match arg_int(enabled) {
+                    Some(enabled @ (0 | 1)) => {
+                        settings.active_expire.store(enabled == 1, Ordering::SeqCst);
+                        RespValue::SimpleString("OK".to_string())
+                    }
+                    _ => RespValue::Error("ERR syntax error".to_string()),
+                },
+                ("object", [key]) => {
+                    let Some(key) = arg_string(key) else {
+                        return RespValue::Error("ERR no such key".to_string());
+                    };
+                    let mut storage = db.write(&key).await;
+                    match storage.entry(&key) {
+                        Some(data) => RespValue::SimpleString(format!(
+                            "Value at:0x0 refcount:{} encoding:{} serializedlength:{} lru:0 lru_seconds_idle:{}",
+                            data.value.refcount(),
+                            data.value.encoding(),
+                            rdb::serialized_length(&data.value),
+                            data.accessed.idle().as_secs()
+                        )),
+                        None => RespValue::Error("ERR no such key".to_string()),
+                    }
+                }
+                ("sleep" | "set-active-expire" | "object", _) => {
+                    RespValue::Error("ERR wrong number of arguments".to_string())
+                }
+                _ => RespValue::Error("ERR unknown subcommand".to_string()),
+            }
+        }
+        "type" => match args.as_slice() {
+            [key] => {
+                let key = arg_string(key).unwrap();
+                let mut storage = db.write(&key).await;
+                match storage.kind(&key) {
+                    Some(kind) => RespValue::SimpleString(kind.name().to_string()),
+                    None => RespValue::SimpleString("none".to_string()),
+                }
+            }
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "swapdb" => match args.as_slice() {
+            [a, b] => {
+                let (a, b) = match (db_index(a, &databases), db_index(b, &databases)) {
+                    (Ok(a), Ok(b)) => (a, b),
+                    (Err(e), _) | (_, Err(e)) => return e,
+                };
+                if a != b {
+                    let (mut a, mut b) = databases.write_pair(a, b).await;
+                    a.swap(&mut b);
+                }
+                RespValue::SimpleString("OK".to_string())
+            }
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "dbsize" => match args.as_slice() {
+            [] => {
+                let shards = db.read_all().await;
+                RespValue::Integer(shards.iter().map(|shard| shard.len_live()).sum::<usize>() as i64)
+            }
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "flushdb" | "flushall" => {
+            // ASYNC is accepted, but flushing in the background is not worth
+            // it for an in-memory map: both modes flush synchronously.
+            let mode = args
+                .first()
+                .and_then(arg_string)
+                .map(|m| m.to_ascii_lowercase());
+            match (mode.as_deref(), args.len()) {
+                (None | Some("sync" | "async"), 0 | 1) => {
+                    match command.as_str() {
+                        "flushall" => {
+                            for db in databases.iter() {
+                                db.write_all()
+                                    .await
+                                    .iter_mut()
+                                    .for_each(|shard| shard.flush());
+                            }
+                        }
+                        _ => db
+                            .write_all()
+                            .await
+                            .iter_mut()
+                            .for_each(|shard| shard.flush()),
+                    }
+                    RespValue::SimpleString("OK".to_string())
+                }
+                _ => RespValue::Error("ERR syntax error".to_string()),
+            }
+        }
+        "randomkey" => match args.as_slice() {
+            [] => RespValue::BulkString(db.random_key().await.map(String::into_bytes)),
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "keys" => match args.as_slice() {
+            [RespValue::BulkString(Some(pattern))] => {
+                let shards = db.read_all().await;
+                RespValue::Array(
+                    shards
+                        .iter()
+                        .flat_map(|shard| shard.keys())
+                        .filter(|key| glob_match(pattern, key.as_bytes()))
+                        .map(|key| RespValue::BulkString(Some(key.clone().into_bytes())))
+                        .collect(),
+                )
+            }
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "scan" => match args.as_slice() {
+            [cursor, options @ ..] => {
+                let Some(cursor) = arg_int(cursor).filter(|cursor| *cursor >= 0) else {
+                    return RespValue::Error("ERR invalid cursor".to_string());
+                };
+                let mut pattern = None;
+                let mut count = 10;
+
+                let mut options = options.iter();
+                while let Some(option) = options.next() {
+                    let option = arg_string(option).unwrap_or_default().to_ascii_lowercase();
+                    match (option.as_str(), options.next()) {
+                        ("match", Some(RespValue::BulkString(Some(value)))) => {
+                            pattern = Some(value.clone())
+                        }
+                        ("count", Some(value)) => match arg_int(value) {
+                            Some(value) if value > 0 => count = value as usize,
+                            _ => {
+                                return RespValue::Error(
+                                    "ERR value is not an integer or out of range".to_string(),
+                                )
+                            }
+                        },
+                        _ => return RespValue::Error("ERR syntax error".to_string()),
+                    }
+                }
+
+                let shards = db.read_all().await;
+                let mut keys = shards
+                    .iter()
+                    .flat_map(|shard| shard.keys())
+                    .collect::<Vec<_>>();
+                keys.sort();
+
+                let start = (cursor as usize).min(keys.len());
+                let end = (start + count).min(keys.len());
+                let next_cursor = if end == keys.len() { 0 } else { end };
+
+                RespValue::Array(vec![
+                    RespValue::BulkString(Some(next_cursor.to_string().into_bytes())),
+                    RespValue::Array(
+                        keys[start..end]
+                            .iter()
+                            .filter(|key| match &pattern {
+                                Some(pattern) => glob_match(pattern, key.as_bytes()),
+                                None => true,
+                            })
+                            .map(|key| RespValue::BulkString(Some(key.as_bytes().to_vec())))
+                            .collect(),
+                    ),
+                ])
+            }
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "expire" | "pexpire" => match args.as_slice() {
+            [key, timeout] => {
+                let key = arg_string(key).unwrap();
+                let Some(timeout) = arg_int(timeout) else {
+                    return RespValue::Error(
+                        "ERR value is not an integer or out of range".to_string(),
+                    );
+                };
+                let millis = match command.as_str() {
+                    "expire" => (timeout.max(0) as u64).checked_mul(1000),
+                    _ => Some(timeout.max(0) as u64),
+                };
+                // Like Redis, refuse expiries past the largest unix time in
+                // milliseconds, even where the monotonic clock would reach it.
+                let millis = millis.filter(|millis| {
+                    rdb::unix_millis()
+                        .checked_add(*millis)
+                        .is_some_and(|at| at <= i64::MAX as u64)
+                });
+                let Some(deadline) = millis.and_then(deadline_in) else {
+                    return RespValue::Error(format!(
+                        "ERR invalid expire time in '{}' command",
+                        command
+                    ));
+                };
+
+                let mut storage = db.write(&key).await;
+                RespValue::Integer(storage.expire(&key, deadline) as i64)
+            }
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "expireat" | "pexpireat" => match args.as_slice() {
+            [key, timestamp] => {
+                let key = arg_string(key).unwrap();
+                let Some(timestamp) = arg_int(timestamp) else {
+                    return RespValue::Error(
+                        "ERR value is not an integer or out of range".to_string(),
+                    );
+                };
+                let at = match command.as_str() {
+                    "expireat" => (timestamp.max(0) as u64).checked_mul(1000),
+                    _ => Some(timestamp.max(0) as u64),
+                };
+                let Some(at) = at else {
+                    return RespValue::Error(format!(
+                        "ERR invalid expire time in '{}' command",
+                        command
+                    ));
+                };
+
+                // Deadlines are monotonic instants, so translate the wall-clock
+                // time into a delay from now.
+                let now = rdb::unix_millis();
+                let mut storage = db.write(&key).await;
+                if at <= now {
+                    return RespValue::Integer(storage.del(&key) as i64);
+                }
+                let Some(deadline) = deadline_in(at - now) else {
+                    return RespValue::Error(format!(
+                        "ERR invalid expire time in '{}' command",
+                        command
+                    ));
+                };
+                RespValue::Integer(storage.expire(&key, deadline) as i64)
+            }
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "persist" => match args.as_slice() {
+            [key] => {
+                let key = arg_string(key).unwrap();
+                let mut storage = db.write(&key).await;
+                RespValue::Integer(storage.persist(&key) as i64)
+            }
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "rename" | "renamenx" => match args.as_slice() {
+            [source, destination] => {
+                let source = arg_string(source).unwrap();
+                let destination = arg_string(destination).unwrap();
+                let mut shards = db.write_keys([source.as_str(), destination.as_str()]).await;
+                if !shards.get(&source).exists(&source) {
+                    RespValue::Error("ERR no such key".to_string())
+                } else if command == "renamenx" {
+                    match shards.get(&destination).exists(&destination) {
+                        true => RespValue::Integer(0),
+                        false => RespValue::Integer(shards.rename(&source, &destination) as i64),
+                    }
+                } else {
+                    shards.rename(&source, &destination);
+                    RespValue::SimpleString("OK".to_string())
+                }
+            }
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "copy" => match args.as_slice() {
+            [source, destination, options @ ..] => {
+                let source = arg_string(source).unwrap();
+                let destination = arg_string(destination).unwrap();
+
+                let mut replace = false;
+                let mut index = state.db;
+                let mut options = options.iter();
+                while let Some(option) = options.next() {
+                    match arg_string(option)
+                        .unwrap_or_default()
+                        .to_lowercase()
+                        .as_str()
+                    {
+                        "replace" => replace = true,
+                        "db" => match options.next().map(|index| db_index(index, &databases)) {
+                            Some(Ok(target)) => index = target,
+                            Some(Err(e)) => return e,
+                            None => return RespValue::Error("ERR syntax error".to_string()),
+                        },
+                        _ => return RespValue::Error("ERR syntax error".to_string()),
+                    }
+                }
+
+                if index == state.db && source == destination {
+                    return RespValue::Error(
+                        "ERR source and destination objects are the same".to_string(),
+                    );
+                }
+                let (mut locked, mut pair);
+                let (shards, target) = match index == state.db {
+                    true => {
+                        locked = db.write_keys([source.as_str(), destination.as_str()]).await;
+                        (&mut locked, None)
+                    }
+                    false => {
+                        pair = databases.write_pair(state.db, index).await;
+                        (&mut pair.0, Some(&mut pair.1))
+                    }
+                };
+                let Some(data) = shards.get(&source).entry(&source).cloned() else {
+                    return RespValue::Integer(0);
+                };
+                let target = target.unwrap_or(shards).get(&destination);
+                if !replace && target.exists(&destination) {
+                    RespValue::Integer(0)
+                } else {
+                    target.insert(destination, data);
+                    RespValue::Integer(1)
+                }
+            }
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "move" => match args.as_slice() {
+            [key, db] => {
+                let key = arg_string(key).unwrap();
+                let db = match db_index(db, &databases) {
+                    Ok(db) => db,
+                    Err(e) => return e,
+                };
+                if db == state.db {
+                    return RespValue::Error(
+                        "ERR source and destination objects are the same".to_string(),
+                    );
+                }
+
+                let (mut shards, mut target) = databases.write_pair(state.db, db).await;
+                if target.get(&key).exists(&key) {
+                    return RespValue::Integer(0);
+                }
+                match shards.get(&key).remove(&key) {
+                    Some(data) => {
+                        target.get(&key).insert(key, data);
+                        RespValue::Integer(1)
+                    }
+                    None => RespValue::Integer(0),
+                }
+            }
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "ttl" | "pttl" => match args.as_slice() {
+            [key] => {
+                let key = arg_string(key).unwrap();
+                let mut storage = db.write(&key).await;
+                match storage.ttl(&key) {
+                    Some(Some(remaining)) => {
+                        let remaining = remaining.as_millis() as i64;
+                        match command.as_str() {
+                            "ttl" => RespValue::Integer((remaining + 500) / 1000),
+                            _ => RespValue::Integer(remaining),
+                        }
+                    }
+                    Some(None) => RespValue::Integer(-1),
+                    None => RespValue::Integer(-2),
+                }
+            }
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "wait" => match args.as_slice() {
+            [numreplicas, timeout] => {
+                let (Some(numreplicas), Some(timeout)) = (arg_int(numreplicas), arg_int(timeout))
+                else {
+                    return RespValue::Error(
+                        "ERR value is not an integer or out of range".to_string(),
+                    );
+                };
+                let deadline = match timeout {
+                    ..0 => return RespValue::Error("ERR timeout is negative".to_string()),
+                    0 => None,
+                    timeout => {
+                        match Instant::now().checked_add(Duration::from_millis(timeout as u64)) {
+                            Some(deadline) => Some(deadline),
+                            None => {
+                                return RespValue::Error("ERR timeout is out of range".to_string())
+                            }
+                        }
+                    }
+                };
+
+                let target = {
+                    let mut replication = replication.write().await;
+                    let target = replication.offset;
+                    if (replication.acked_count(target) as i64) < numreplicas {
+                        replication.request_ack();
+                    }
+                    target
+                };
+
+                loop {
+                    let replication = replication.read().await;
+                    let acks = replication.acks.clone();
+                    let notified = acks.notified();
+                    let count = replication.acked_count(target);
+                    drop(replication);
+
+                    if count as i64 >= numreplicas {
+                        return RespValue::Integer(count as i64);
+                    }
+
+                    match deadline {
+                        Some(deadline) => {
+                            if tokio::time::timeout_at(deadline, notified).await.is_err() {
+                                return RespValue::Integer(count as i64);
+                            }
+                        }
+                        None => notified.await,
+                    }
+                }
+            }
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "save" => {
+            if settings
+                .save_status
+                .bgsave_in_progress
+                .load(Ordering::SeqCst)
+            {
+                return RespValue::Error("ERR Background save already in progress".to_string());
+            }
+
+            let path = settings.config.read().await.rdb_path();
+            let locked = databases.read_all().await;
+            let storages = locked
+                .iter()
+                .map(|shards| shards.iter().map(|shard| &**shard).collect())
+                .collect::<Vec<_>>();
+            match rdb::save(&path, &storages) {
+                Ok(()) => {
+                    settings
+                        .save_status
+                        .last_save_time
+                        .store(rdb::unix_millis() / 1000, Ordering::SeqCst);
+                    RespValue::SimpleString("OK".to_string())
+                }
+                Err(e) => RespValue::Error(format!("ERR failed to save RDB file: {}", e)),
+            }
+        }
+        "bgsave" => {
+            if settings
+                .save_status
+                .bgsave_in_progress
+                .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+                .is_err()
+            {
+                return RespValue::Error("ERR Background save already in progress".to_string());
+            }
+
+            let path = settings.config.read().await.rdb_path();
+            let snapshot = databases
+                .read_all()
+                .await
+                .iter()
+                .map(|shards| {
+                    shards
+                        .iter()
+                        .map(|shard| Storage::clone(shard))
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>();
+            let settings = settings.clone();
+            tokio::spawn(async move {
+                let result = tokio::task::spawn_blocking(move || {
+                    let storages = snapshot
+                        .iter()
+                        .map(|shards| shards.iter().collect())
+                        .collect::<Vec<_>>();
+                    rdb::save(&path, &storages)
+                })
+                .await;
+                match result {
+                    Ok(Ok(())) => settings
+                        .save_status
+                        .last_save_time
+                        .store(rdb::unix_millis() / 1000, Ordering::SeqCst),
+                    Ok(Err(e)) => eprintln!("background save failed: {}", e),
+                    Err(e) => eprintln!("background save failed: {}", e),
+                }
+                settings
+                    .save_status
+                    .bgsave_in_progress
+                    .store(false, Ordering::SeqCst);
+            });
+
+            RespValue::SimpleString("Background saving started".to_string())
+        }
+        "config" => match args.as_slice() {
+            [subcommand, parameters @ ..] => {
+                match arg_string(subcommand)
+                    .unwrap_or_default()
+                    .to_ascii_lowercase()
+                    .as_str()
+                {
+                    "get" if !parameters.is_empty() => {
+                        let values = settings.config.read().await.parameters();
+                        RespValue::Map(
+                            values
+                                .into_iter()
+                                .filter(|(name, _)| {
+                                    parameters.iter().any(|parameter| match parameter {
+                                        RespValue::BulkString(Some(pattern)) => glob_match(
+                                            &pattern.to_ascii_lowercase(),
+                                            name.as_bytes(),
+                                        ),
+                                        _ => false,
+                                    })
+                                })
+                                .map(|(name, value)| {
+                                    (
+                                        RespValue::BulkString(Some(name.as_bytes().to_vec())),
+                                        RespValue::BulkString(Some(value.into_bytes())),
+                                    )
+                                })
+                                .collect(),
+                        )
+                    }
+                    "set" if !parameters.is_empty() && parameters.len() % 2 == 0 => {
+                        // Enabling `appendonly` rewrites the append-only file,
+                        // which no write may run concurrently with.
+                        let _exclusive = match state.executing {
+                            true => None,
+                            false => Some(settings.transactions.write().await),
+                        };
+                        // Every pair is applied to a copy first, so that
+                        // nothing changes unless they are all valid.
+                        let mut config = settings.config.write().await;
+                        let mut staged = config.clone();
+                        for pair in parameters.chunks_exact(2) {
+                            let (Some(name), Some(value)) =
+                                (arg_string(&pair[0]), arg_string(&pair[1]))
+                            else {
+                                return RespValue::Error("ERR syntax error".to_string());
+                            };
+                            if let Err(e) = staged.set(&name.to_ascii_lowercase(), &value) {
+                                return RespValue::Error(format!("ERR CONFIG SET failed - {}", e));
+                            }
+                        }
+                        // Appending to a file missing the existing keys would
+                        // lose them on the next restart.
+                        if staged.appendonly && !config.appendonly {
+                            let mut aof = settings.aof.lock().await;
+                            let locked = databases.read_all().await;
+                            let storages = locked
+                                .iter()
+                                .map(|shards| shards.iter().map(|shard| &**shard).collect())
+                                .collect::<Vec<_>>();
+                            if let Err(e) = aof::rewrite(&staged.aof_path(), &storages) {
+                                return RespValue::Error(format!(
+                                    "ERR CONFIG SET failed - failed to rewrite append-only file: {}",
+                                    e
+                                ));
+                            }
+                            // Reopened on the next write, past the rewrite.
+                            *aof = None;
+                        }
+                        *config = staged;
+                        let maxmemory = config.maxmemory;
+                        drop(config);
+                        databases.track_memory(maxmemory > 0).await;
+                        RespValue::SimpleString("OK".to_string())
+                    }
+                    "get" | "set" => RespValue::Error("ERR wrong number of arguments".to_string()),
+                    _ => RespValue::Error("ERR unknown subcommand".to_string()),
+                }
+            }
+            _ => RespValue::Error("ERR wrong number of arguments".to_string()),
+        },
+        "replconf" => RespValue::SimpleString("OK".to_string()),
+        "psync" => {
+            let offset = replication.read().await.offset;
+            RespValue::SimpleString(format!("FULLRESYNC {} {}", REPLICATION_ID, offset))
+        }
+        _ => RespValue::Error("ERR unknown command".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    /// A configuration with a directory of its own, so that servers started
+    /// with it load the files written by the previous ones.
+    fn test_config() -> Config {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "redis-test-{}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::SeqCst)
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        Config {
+            dir: dir.to_string_lossy().into_owned(),
+            dbfilename: "dump.rdb".to_string(),
+            maxmemory: 0,
+            maxmemory_policy: Policy::NoEviction,
+            appendonly: false,
+            appendfilename: "appendonly.aof".to_string(),
+            appendfsync: Fsync::EverySec,
+        }
+    }
+
+    /// Starts a server on a free port, replicating from the master at
+    /// `replicaof` if given, and returns the port.
+    async fn start_server(replicaof: Option<(String, u16)>) -> u16 {
+        start_server_with(test_config(), replicaof).await
+    }
+
+    /// Like `start_server`, but loading the keyspace as configured by
+    /// `config` first.
+    async fn start_server_with(config: Config, replicaof: Option<(String, u16)>) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let settings = Arc::new(Settings {
+            port,
+            replicaof: replicaof.clone(),
+            config: RwLock::new(config),
+            save_status: rdb::SaveStatus::new(),
+            aof: Mutex::new(None),
+            pubsub: Mutex::new(PubSub::new()),
+            clients: Arc::new(std::sync::Mutex::new(Clients::new())),
+            active_expire: AtomicBool::new(true),
+            stats: Stats::new(),
+            transactions: RwLock::new(()),
+        });
+        let replication = Arc::new(RwLock::new(Replication::new()));
+        let databases = load_databases(16, &settings, &replication).await.unwrap();
+        if let Some((host, master_port)) = &replicaof {
+            sync_with_master(host, *master_port, &databases, &settings, &replication)
+                .await
+                .unwrap();
+        }
+
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let databases = databases.clone();
+                let settings = settings.clone();
+                let replication = replication.clone();
+                tokio::spawn(async move {
+                    handle_connection(stream, databases, settings, replication).await;
+                });
+            }
+        });
+        port
+    }
+
+    struct Client(resp::RespParser);
+
+    impl Client {
+        async fn connect(port: u16) -> Client {
+            let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+            Client(resp::RespParser::new(stream))
+        }
+
+        /// Sends raw bytes, without any framing.
+        async fn send_raw(&mut self, bytes: &[u8]) {
+            self.0.write_all(bytes.to_vec()).await.unwrap();
+            self.0.flush().await.unwrap();
+        }
+
+        /// Sends a command made of the space-separated words of `command`
+        /// and returns the encoded reply.
+        async fn send(&mut self, command: &str) -> Vec<u8> {
+            let command = RespValue::Array(
+                command
+                    .split(' ')
+                    .map(|word| RespValue::BulkString(Some(word.as_bytes().to_vec())))
+                    .collect(),
+            );
+            self.send_raw(&command.to_bytes()).await;
+            self.reply().await
+        }
+
+        async fn reply(&mut self) -> Vec<u8> {
+            self.0.parse().await.unwrap().to_bytes()
+        }
+    }
+
+    #[tokio::test]
+    async fn inline_commands_are_parsed() {
+        let mut client = Client::connect(start_server(None).await).await;
+        client.send_raw(b"ECHO hello\r\n").await;
+        assert_eq!(client.reply().await, b"$5\r\nhello\r\n");
+        client.send_raw(b"SET  key   value\r\n").await;
+        assert_eq!(client.reply().await, b"+OK\r\n");
+        assert_eq!(client.send("GET key").await, b"$5\r\nvalue\r\n");
+    }
+
+    #[tokio::test]
+    async fn pipelined_commands_are_all_answered() {
+        let mut client = Client::connect(start_server(None).await).await;
+        client.send_raw(b"PING\r\nPING\r\nPING\r\n").await;
+        for _ in 0..3 {
+            assert_eq!(client.reply().await, b"+PONG\r\n");
+        }
+        assert_eq!(client.send("ECHO done").await, b"$4\r\ndone\r\n");
+    }
+
+    #[tokio::test]
+    async fn writes_propagate_to_replicas() {
+        let master_port = start_server(None).await;
+        let replica_port = start_server(Some(("127.0.0.1".to_string(), master_port))).await;
+        let mut master = Client::connect(master_port).await;
+        let mut replica = Client::connect(replica_port).await;
+
+        assert_eq!(master.send("SET foo bar").await, b"+OK\r\n");
+        assert_eq!(master.send("SET gone soon").await, b"+OK\r\n");
+        assert_eq!(master.send("DEL gone").await, b":1\r\n");
+        assert_eq!(master.send("EXPIRE foo 100").await, b":1\r\n");
+        // WAIT returns once the replica acknowledged every write so far.
+        assert_eq!(master.send("WAIT 1 5000").await, b":1\r\n");
+
+        assert_eq!(replica.send("GET foo").await, b"$3\r\nbar\r\n");
+        assert_eq!(replica.send("EXISTS gone").await, b":0\r\n");
+        let ttl = replica.send("TTL foo").await;
+        assert!(ttl == b":100\r\n" || ttl == b":99\r\n");
+    }
+
+    #[tokio::test]
+    async fn hset_counts_only_new_fields() {
+        let mut client = Client::connect(start_server(None).await).await;
+        assert_eq!(client.send("HSET h a 1 b 2").await, b":2\r\n");
+        assert_eq!(client.send("HSET h a 10 c 3").await, b":1\r\n");
+        assert_eq!(client.send("HSET h a 11 a 12").await, b":0\r\n");
+        assert_eq!(client.send("HGET h a").await, b"$2\r\n12\r\n");
+        assert_eq!(client.send("HGET h missing").await, b"$-1\r\n");
+
+        assert_eq!(client.send("SET s v").await, b"+OK\r\n");
+        assert_eq!(
+            client.send("HSET s a 1").await,
+            format!("-{}\r\n", WRONGTYPE).into_bytes()
+        );
+    }
+
+    #[tokio::test]
+    async fn exec_aborts_when_a_watched_key_changes() {
+        let port = start_server(None).await;
+        let mut client = Client::connect(port).await;
+        let mut other = Client::connect(port).await;
+
+        assert_eq!(client.send("SET counter 1").await, b"+OK\r\n");
+        assert_eq!(client.send("WATCH counter").await, b"+OK\r\n");
+        assert_eq!(other.send("INCR counter").await, b":2\r\n");
+        assert_eq!(client.send("MULTI").await, b"+OK\r\n");
+        assert_eq!(client.send("SET counter 10").await, b"+QUEUED\r\n");
+        assert_eq!(client.send("EXEC").await, b"*-1\r\n");
+        assert_eq!(client.send("GET counter").await, b"$1\r\n2\r\n");
+
+        // EXEC clears the watched keys, so the next transaction goes through.
+        assert_eq!(client.send("MULTI").await, b"+OK\r\n");
+        assert_eq!(client.send("SET counter 10").await, b"+QUEUED\r\n");
+        assert_eq!(client.send("EXEC").await, b"*1\r\n+OK\r\n");
+    }
+
+    #[tokio::test]
+    async fn exec_runs_when_watched_keys_are_untouched() {
+        let port = start_server(None).await;
+        let mut client = Client::connect(port).await;
+        let mut other = Client::connect(port).await;
+
+        assert_eq!(client.send("WATCH missing counter").await, b"+OK\r\n");
+        assert_eq!(other.send("SET unrelated 1").await, b"+OK\r\n");
+        assert_eq!(client.send("MULTI").await, b"+OK\r\n");
+        assert_eq!(client.send("INCR counter").await, b"+QUEUED\r\n");
+        assert_eq!(client.send("EXEC").await, b"*1\r\n:1\r\n");
+
+        assert_eq!(client.send("WATCH counter").await, b"+OK\r\n");
+        assert_eq!(other.send("SET counter 5").await, b"+OK\r\n");
+        assert_eq!(client.send("UNWATCH").await, b"+OK\r\n");
+        assert_eq!(client.send("MULTI").await, b"+OK\r\n");
+        assert_eq!(client.send("INCR counter").await, b"+QUEUED\r\n");
+        assert_eq!(client.send("EXEC").await, b"*1\r\n:6\r\n");
+    }
+
+    #[tokio::test]
+    async fn rename_onto_itself_keeps_the_key() {
+        let mut client = Client::connect(start_server(None).await).await;
+        assert_eq!(client.send("SET k v EX 100").await, b"+OK\r\n");
+        assert_eq!(client.send("RENAME k k").await, b"+OK\r\n");
+        assert_eq!(client.send("GET k").await, b"$1\r\nv\r\n");
+        let ttl = client.send("TTL k").await;
+        assert!(ttl == b":100\r\n" || ttl == b":99\r\n");
+        assert_eq!(client.send("RENAMENX k k").await, b":0\r\n");
+        assert_eq!(
+            client.send("RENAME missing missing").await,
+            b"-ERR no such key\r\n"
+        );
+
+        // Renaming elsewhere carries the TTL along.
+        assert_eq!(client.send("RENAME k j").await, b"+OK\r\n");
+        assert_eq!(client.send("EXISTS k").await, b":0\r\n");
+        let ttl = client.send("TTL j").await;
+        assert!(ttl == b":100\r\n" || ttl == b":99\r\n");
+    }
+
+    #[tokio::test]
+    async fn string_commands_on_a_list_are_wrongtype() {
+        let mut client = Client::connect(start_server(None).await).await;
+        assert_eq!(client.send("LPUSH list a").await, b":1\r\n");
+        let wrongtype = format!("-{}\r\n", WRONGTYPE).into_bytes();
+        for command in [
+            "GET list",
+            "APPEND list b",
+            "INCR list",
+            "STRLEN list",
+            "GETRANGE list 0 -1",
+        ] {
+            assert_eq!(client.send(command).await, wrongtype, "{}", command);
+        }
+        assert_eq!(client.send("LRANGE list 0 -1").await, b"*1\r\n$1\r\na\r\n");
+    }
+
+    #[tokio::test]
+    async fn bitcount_counts_byte_and_bit_ranges() {
+        let mut client = Client::connect(start_server(None).await).await;
+        assert_eq!(client.send("SET mykey foobar").await, b"+OK\r\n");
+        assert_eq!(client.send("BITCOUNT mykey").await, b":26\r\n");
+        assert_eq!(client.send("BITCOUNT mykey 0 0").await, b":4\r\n");
+        assert_eq!(client.send("BITCOUNT mykey 1 1").await, b":6\r\n");
+        assert_eq!(client.send("BITCOUNT mykey 1 1 BYTE").await, b":6\r\n");
+        assert_eq!(client.send("BITCOUNT mykey 5 30 BIT").await, b":17\r\n");
+        assert_eq!(client.send("BITCOUNT mykey -2 -1").await, b":7\r\n");
+        assert_eq!(client.send("BITCOUNT mykey 3 1").await, b":0\r\n");
+        assert_eq!(client.send("BITCOUNT missing").await, b":0\r\n");
+        assert_eq!(client.send("BITCOUNT missing 0 -1 BIT").await, b":0\r\n");
+    }
+
+    #[tokio::test]
+    async fn debug_sleep_does_not_hold_up_other_connections() {
+        let port = start_server(None).await;
+        let mut sleeper = Client::connect(port).await;
+        let mut client = Client::connect(port).await;
+        let mut other = Client::connect(port).await;
+
+        let sleeping = tokio::spawn(async move { sleeper.send("DEBUG SLEEP 1").await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let start = Instant::now();
+        assert_eq!(client.send("MULTI").await, b"+OK\r\n");
+        assert_eq!(client.send("SET k v").await, b"+QUEUED\r\n");
+        assert_eq!(client.send("EXEC").await, b"*1\r\n+OK\r\n");
+        assert_eq!(other.send("GET k").await, b"$1\r\nv\r\n");
+        assert!(start.elapsed() < Duration::from_millis(500));
+        assert!(!sleeping.is_finished());
+        assert_eq!(sleeping.await.unwrap(), b"+OK\r\n");
+    }
+
+    #[tokio::test]
+    async fn replconf_replies_with_a_status() {
+        let mut client = Client::connect(start_server(None).await).await;
+        assert_eq!(
+            client.send("REPLCONF listening-port 6380").await,
+            b"+OK\r\n"
+        );
+        assert_eq!(client.send("REPLCONF capa psync2").await, b"+OK\r\n");
+    }
+
+    /// Plays the master's side of the replication handshake with the replica
+    /// connecting to the returned port, then streams it `commands`.
+    async fn fake_master(commands: &'static [u8]) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut replica = resp::RespParser::new(stream);
+            for reply in ["+PONG\r\n", "+OK\r\n", "+OK\r\n", "+FULLRESYNC abc 0\r\n"] {
+                replica.parse().await.unwrap();
+                replica.write_all(reply.as_bytes().to_vec()).await.unwrap();
+                replica.flush().await.unwrap();
+            }
+            let rdb = decode_hex_string(EMPTY_RDB_HEX).unwrap();
+            send_rdb(&mut replica, &rdb).await.unwrap();
+            replica.write_all(commands.to_vec()).await.unwrap();
+            replica.flush().await.unwrap();
+            // Keep the link open for the replica's acknowledgements.
+            while replica.parse().await.is_ok() {}
+        });
+        port
+    }
+
+    #[tokio::test]
+    async fn replicated_commands_missing_arguments_are_ignored() {
+        let master_port = fake_master(
+            b"*1\r\n$3\r\nGET\r\n*1\r\n$4\r\nHGET\r\n*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n",
+        )
+        .await;
+        let replica_port = start_server(Some(("127.0.0.1".to_string(), master_port))).await;
+        let mut replica = Client::connect(replica_port).await;
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while replica.send("GET k").await != b"$1\r\nv\r\n" {
+            assert!(Instant::now() < deadline, "SET never replicated");
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn blpop_wakes_on_any_of_its_keys() {
+        let port = start_server(None).await;
+        let mut blocked = Client::connect(port).await;
+        let mut client = Client::connect(port).await;
+
+        let popped = tokio::spawn(async move {
+            let popped = blocked.send("BLPOP a b 5").await;
+            (popped, blocked.send("BLPOP a b 0.05").await)
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(client.send("LPUSH b x").await, b":1\r\n");
+
+        let (popped, timed_out) = popped.await.unwrap();
+        assert_eq!(popped, b"*2\r\n$1\r\nb\r\n$1\r\nx\r\n");
+        assert_eq!(timed_out, b"*-1\r\n");
+    }
+
+    #[tokio::test]
+    async fn xread_wakes_on_any_stream_and_times_out_with_a_nil_array() {
+        let port = start_server(None).await;
+        let mut blocked = Client::connect(port).await;
+        let mut client = Client::connect(port).await;
+
+        let read = tokio::spawn(async move {
+            let read = blocked.send("XREAD BLOCK 5000 STREAMS s t $ $").await;
+            let timed_out = blocked.send("XREAD BLOCK 50 STREAMS s t $ $").await;
+            (read, timed_out)
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(client.send("XADD t 1-1 f v").await, b"$3\r\n1-1\r\n");
+
+        let (read, timed_out) = read.await.unwrap();
+        assert_eq!(
+            read,
+            b"*1\r\n*2\r\n$1\r\nt\r\n*1\r\n*2\r\n$3\r\n1-1\r\n*2\r\n$1\r\nf\r\n$1\r\nv\r\n"
+        );
+        assert_eq!(timed_out, b"*-1\r\n");
+        assert_eq!(client.send("XREAD STREAMS t 1-1").await, b"*-1\r\n");
+    }
+
+    #[tokio::test]
+    async fn config_set_applies_nothing_unless_every_pair_is_valid() {
+        let mut client = Client::connect(start_server(None).await).await;
+        assert_eq!(
+            client.send("CONFIG SET maxmemory 10 bogus x").await,
+            b"-ERR CONFIG SET failed - Unknown option or number of arguments for CONFIG SET - 'bogus'\r\n"
+        );
+        assert_eq!(
+            client
+                .send("CONFIG SET maxmemory 10 maxmemory-policy sometimes")
+                .await[..22],
+            b"-ERR CONFIG SET failed"[..]
+        );
+        assert_eq!(
+            client.send("CONFIG GET maxmemory").await,
+            b"*2\r\n$9\r\nmaxmemory\r\n$1\r\n0\r\n"
+        );
+        assert_eq!(
+            client.send("CONFIG SET maxmemory").await,
+            b"-ERR wrong number of arguments\r\n"
+        );
+
+        assert_eq!(
+            client
+                .send("CONFIG SET maxmemory 10mb maxmemory-policy allkeys-lru")
+                .await,
+            b"+OK\r\n"
+        );
+        assert_eq!(
+            client.send("CONFIG GET maxmemory").await,
+            b"*2\r\n$9\r\nmaxmemory\r\n$8\r\n10485760\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn pops_with_and_without_a_count() {
+        let mut client = Client::connect(start_server(None).await).await;
+        assert_eq!(client.send("LPOP missing").await, b"$-1\r\n");
+        assert_eq!(client.send("LPOP missing 2").await, b"*-1\r\n");
+        assert_eq!(client.send("RPOP missing 0").await, b"*-1\r\n");
+
+        assert_eq!(client.send("RPUSH list a b c").await, b":3\r\n");
+        assert_eq!(
+            client.send("LPOP list 2").await,
+            b"*2\r\n$1\r\na\r\n$1\r\nb\r\n"
+        );
+        assert_eq!(client.send("RPOP list 5").await, b"*1\r\n$1\r\nc\r\n");
+        assert_eq!(client.send("EXISTS list").await, b":0\r\n");
+
+        assert_eq!(client.send("RPUSH list a b").await, b":2\r\n");
+        assert_eq!(client.send("RPOP list").await, b"$1\r\nb\r\n");
+        assert_eq!(client.send("LPOP list").await, b"$1\r\na\r\n");
+        assert_eq!(client.send("EXISTS list").await, b":0\r\n");
+        assert_eq!(client.send("LPOP list").await, b"$-1\r\n");
+        assert_eq!(
+            client.send("LPOP list -1").await,
+            b"-ERR value is out of range, must be positive\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn wait_rejects_a_negative_timeout() {
+        let mut client = Client::connect(start_server(None).await).await;
+        assert_eq!(
+            client.send("WAIT 1 -1").await,
+            b"-ERR timeout is negative\r\n"
+        );
+        assert_eq!(client.send("WAIT 0 10").await, b":0\r\n");
+    }
+
+    #[tokio::test]
+    async fn expire_errors_name_the_command() {
+        let mut client = Client::connect(start_server(None).await).await;
+        assert_eq!(client.send("SET key value").await, b"+OK\r\n");
+        assert_eq!(
+            client.send("EXPIRE key 9223372036854775807").await,
+            b"-ERR invalid expire time in 'expire' command\r\n"
+        );
+        assert_eq!(
+            client.send("PEXPIRE key 9223372036854775807").await,
+            b"-ERR invalid expire time in 'pexpire' command\r\n"
+        );
+        assert_eq!(client.send("TTL key").await, b":-1\r\n");
+    }
+
+    #[tokio::test]
+    async fn published_messages_reach_channel_and_pattern_subscribers() {
+        let port = start_server(None).await;
+        let mut subscriber = Client::connect(port).await;
+        let mut publisher = Client::connect(port).await;
+        assert_eq!(
+            subscriber.send("SUBSCRIBE news").await,
+            b"*3\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n:1\r\n"
+        );
+        assert_eq!(
+            subscriber.send("PSUBSCRIBE n*").await,
+            b"*3\r\n$10\r\npsubscribe\r\n$2\r\nn*\r\n:2\r\n"
+        );
+
+        assert_eq!(publisher.send("PUBLISH news hello").await, b":2\r\n");
+        assert_eq!(publisher.send("PUBLISH other hello").await, b":0\r\n");
+
+        // Each subscription forwards on its own, in no particular order.
+        let mut messages = vec![subscriber.reply().await, subscriber.reply().await];
+        messages.sort();
+        assert_eq!(
+            messages,
+            [
+                b"*3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$5\r\nhello\r\n".to_vec(),
+                b"*4\r\n$8\r\npmessage\r\n$2\r\nn*\r\n$4\r\nnews\r\n$5\r\nhello\r\n".to_vec(),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn resp3_subscribers_receive_pushes() {
+        let port = start_server(None).await;
+        let mut subscriber = Client::connect(port).await;
+        let mut publisher = Client::connect(port).await;
+        assert_eq!(subscriber.send("HELLO 3").await[..1], *b"%");
+        assert_eq!(
+            subscriber.send("SUBSCRIBE news").await,
+            b">3\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n:1\r\n"
+        );
+        // Commands still work once subscribed, replying in band.
+        assert_eq!(subscriber.send("SET key value").await, b"+OK\r\n");
+
+        assert_eq!(publisher.send("PUBLISH news hello").await, b":1\r\n");
+        assert_eq!(
+            subscriber.reply().await,
+            b">3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$5\r\nhello\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn subscription_replies_carry_the_running_count() {
+        let mut client = Client::connect(start_server(None).await).await;
+        client.send_raw(b"SUBSCRIBE a b\r\n").await;
+        assert_eq!(
+            client.reply().await,
+            b"*3\r\n$9\r\nsubscribe\r\n$1\r\na\r\n:1\r\n"
+        );
+        assert_eq!(
+            client.reply().await,
+            b"*3\r\n$9\r\nsubscribe\r\n$1\r\nb\r\n:2\r\n"
+        );
+        assert_eq!(
+            client.send("PSUBSCRIBE p*").await,
+            b"*3\r\n$10\r\npsubscribe\r\n$2\r\np*\r\n:3\r\n"
+        );
+        assert_eq!(
+            client.send("UNSUBSCRIBE a").await,
+            b"*3\r\n$11\r\nunsubscribe\r\n$1\r\na\r\n:2\r\n"
+        );
+        assert_eq!(
+            client.send("UNSUBSCRIBE").await,
+            b"*3\r\n$11\r\nunsubscribe\r\n$1\r\nb\r\n:1\r\n"
+        );
+        assert_eq!(
+            client.send("PUNSUBSCRIBE").await,
+            b"*3\r\n$12\r\npunsubscribe\r\n$2\r\np*\r\n:0\r\n"
+        );
+        assert_eq!(
+            client.send("UNSUBSCRIBE").await,
+            b"*3\r\n$11\r\nunsubscribe\r\n$-1\r\n:0\r\n"
+        );
+        // Out of subscribe mode once the count is back to zero.
+        assert_eq!(client.send("PING").await, b"+PONG\r\n");
+    }
+
+    #[tokio::test]
+    async fn ping_while_subscribed_replies_with_pong() {
+        let mut client = Client::connect(start_server(None).await).await;
+        assert_eq!(
+            client.send("SUBSCRIBE news").await,
+            b"*3\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n:1\r\n"
+        );
+        assert_eq!(client.send("PING").await, b"*2\r\n$4\r\npong\r\n$0\r\n\r\n");
+        assert_eq!(
+            client.send("PING hello").await,
+            b"*2\r\n$4\r\npong\r\n$5\r\nhello\r\n"
+        );
+        assert_eq!(
+            client.send("GET key").await,
+            b"-ERR Can't execute 'get': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn mset_and_mget_span_shards() {
+        let mut client = Client::connect(start_server(None).await).await;
+        assert_eq!(
+            client.send("MSET a 1 b").await,
+            b"-ERR wrong number of arguments\r\n"
+        );
+        assert_eq!(client.send("RPUSH list x").await, b":1\r\n");
+        assert_eq!(client.send("HSET hash f v").await, b":1\r\n");
+        // MSET replaces keys of any type.
+        assert_eq!(client.send("MSET a 1 b 2 list 3").await, b"+OK\r\n");
+        assert_eq!(
+            client.send("MGET a b missing list hash").await,
+            b"*5\r\n$1\r\n1\r\n$1\r\n2\r\n$-1\r\n$1\r\n3\r\n$-1\r\n"
+        );
+
+        // A key named twice takes the last value.
+        assert_eq!(client.send("MSET a x a y").await, b"+OK\r\n");
+        assert_eq!(
+            client.send("MGET a a").await,
+            b"*2\r\n$1\r\ny\r\n$1\r\ny\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn pushes_build_the_list_from_either_end() {
+        let mut client = Client::connect(start_server(None).await).await;
+        assert_eq!(client.send("RPUSH list a b c").await, b":3\r\n");
+        // LPUSH inserts its values one by one, reversing them.
+        assert_eq!(client.send("LPUSH list z y").await, b":5\r\n");
+        assert_eq!(
+            client.send("LRANGE list 0 -1").await,
+            b"*5\r\n$1\r\ny\r\n$1\r\nz\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n"
+        );
+        assert_eq!(
+            client.send("LRANGE list -2 99").await,
+            b"*2\r\n$1\r\nb\r\n$1\r\nc\r\n"
+        );
+        assert_eq!(client.send("LRANGE list 3 1").await, b"*0\r\n");
+        assert_eq!(client.send("LRANGE missing 0 -1").await, b"*0\r\n");
+
+        assert_eq!(client.send("SET string value").await, b"+OK\r\n");
+        for command in ["LPUSH string x", "RPUSH string x"] {
+            assert_eq!(
+                client.send(command).await,
+                format!("-{}\r\n", WRONGTYPE).as_bytes()
+            );
+        }
+        assert_eq!(client.send("GET string").await, b"$5\r\nvalue\r\n");
+    }
+
+    #[tokio::test]
+    async fn saved_keys_are_loaded_on_restart() {
+        let config = test_config();
+        let mut client = Client::connect(start_server_with(config.clone(), None).await).await;
+        assert_eq!(client.send("SET string value EX 100").await, b"+OK\r\n");
+        assert_eq!(client.send("RPUSH list a b").await, b":2\r\n");
+        assert_eq!(client.send("HSET hash f v").await, b":1\r\n");
+        assert_eq!(client.send("SADD set m").await, b":1\r\n");
+        assert_eq!(client.send("ZADD zset 1.5 m").await, b":1\r\n");
+        assert_eq!(client.send("SELECT 1").await, b"+OK\r\n");
+        assert_eq!(client.send("SET other db").await, b"+OK\r\n");
+        assert_eq!(client.send("SAVE").await, b"+OK\r\n");
+
+        let mut client = Client::connect(start_server_with(config, None).await).await;
+        assert_eq!(client.send("DBSIZE").await, b":5\r\n");
+        assert_eq!(client.send("GET string").await, b"$5\r\nvalue\r\n");
+        let ttl = client.send("TTL string").await;
+        assert!(ttl == b":100\r\n" || ttl == b":99\r\n");
+        assert_eq!(
+            client.send("LRANGE list 0 -1").await,
+            b"*2\r\n$1\r\na\r\n$1\r\nb\r\n"
+        );
+        assert_eq!(client.send("HGET hash f").await, b"$1\r\nv\r\n");
+        assert_eq!(client.send("SISMEMBER set m").await, b":1\r\n");
+        assert_eq!(client.send("ZSCORE zset m").await, b"$3\r\n1.5\r\n");
+        assert_eq!(client.send("SELECT 1").await, b"+OK\r\n");
+        assert_eq!(client.send("GET other").await, b"$2\r\ndb\r\n");
+    }
+
+    #[tokio::test]
+    async fn append_only_file_is_replayed_on_restart() {
+        let config = Config {
+            appendonly: true,
+            ..test_config()
+        };
+        let mut client = Client::connect(start_server_with(config.clone(), None).await).await;
+        assert_eq!(client.send("SET string value EX 100").await, b"+OK\r\n");
+        assert_eq!(client.send("RPUSH list a b c").await, b":3\r\n");
+        assert_eq!(client.send("LPOP list").await, b"$1\r\na\r\n");
+        assert_eq!(client.send("SET gone value").await, b"+OK\r\n");
+        assert_eq!(client.send("DEL gone").await, b":1\r\n");
+        assert_eq!(client.send("SET short value").await, b"+OK\r\n");
+        assert_eq!(client.send("PEXPIRE short 50").await, b":1\r\n");
+        assert_eq!(client.send("SELECT 1").await, b"+OK\r\n");
+        assert_eq!(client.send("SET other db").await, b"+OK\r\n");
+
+        // Relative expiries are logged as deadlines, so time spent down
+        // counts against them.
+        let aof = std::fs::read(config.aof_path()).unwrap();
+        assert!(aof.windows(9).any(|word| word == b"PEXPIREAT"));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        let mut client = Client::connect(start_server_with(config, None).await).await;
+        assert_eq!(client.send("DBSIZE").await, b":2\r\n");
+        assert_eq!(client.send("GET string").await, b"$5\r\nvalue\r\n");
+        let ttl = client.send("TTL string").await;
+        assert!(ttl == b":100\r\n" || ttl == b":99\r\n");
+        assert_eq!(
+            client.send("LRANGE list 0 -1").await,
+            b"*2\r\n$1\r\nb\r\n$1\r\nc\r\n"
+        );
+        assert_eq!(client.send("EXISTS gone short").await, b":0\r\n");
+        assert_eq!(client.send("SELECT 1").await, b"+OK\r\n");
+        assert_eq!(client.send("GET other").await, b"$2\r\ndb\r\n");
+    }
+
+    #[tokio::test]
+    async fn maxmemory_refuses_or_evicts_per_policy() {
+        let mut client = Client::connect(start_server(None).await).await;
+        let value = "x".repeat(1000);
+        for i in 0..10 {
+            assert_eq!(
+                client.send(&format!("SET key{} {}", i, value)).await,
+                b"+OK\r\n"
+            );
+        }
+
+        assert_eq!(client.send("CONFIG SET maxmemory 3000").await, b"+OK\r\n");
+        assert_eq!(
+            client.send("SET new value").await,
+            format!("-{}\r\n", eviction::OOM).as_bytes()
+        );
+        // Reads and deletions are still allowed.
+        assert_eq!(client.send("STRLEN key0").await, b":1000\r\n");
+        assert_eq!(client.send("DEL key0").await, b":1\r\n");
+        assert_eq!(client.send("DBSIZE").await, b":9\r\n");
+
+        assert_eq!(
+            client.send("CONFIG SET maxmemory-policy allkeys-lru").await,
+            b"+OK\r\n"
+        );
+        assert_eq!(client.send("SET new value").await, b"+OK\r\n");
+        assert_eq!(client.send("GET new").await, b"$5\r\nvalue\r\n");
+        let dbsize = client.send("DBSIZE").await;
+        let dbsize = std::str::from_utf8(&dbsize[1..dbsize.len() - 2]).unwrap();
+        // Room was made for the new key, and no more than needed.
+        assert!((1..=4).contains(&dbsize.parse::<i64>().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn move_relocates_keys_between_databases() {
+        let mut client = Client::connect(start_server(None).await).await;
+        assert_eq!(client.send("SET key value EX 100").await, b"+OK\r\n");
+        assert_eq!(client.send("MOVE key 1").await, b":1\r\n");
+        assert_eq!(client.send("EXISTS key").await, b":0\r\n");
+        assert_eq!(client.send("MOVE key 1").await, b":0\r\n");
+
+        assert_eq!(client.send("SELECT 1").await, b"+OK\r\n");
+        assert_eq!(client.send("GET key").await, b"$5\r\nvalue\r\n");
+        let ttl = client.send("TTL key").await;
+        assert!(ttl == b":100\r\n" || ttl == b":99\r\n");
+
+        // An existing key in the destination is left alone.
+        assert_eq!(client.send("SET taken 1").await, b"+OK\r\n");
+        assert_eq!(client.send("SELECT 0").await, b"+OK\r\n");
+        assert_eq!(client.send("SET taken 0").await, b"+OK\r\n");
+        assert_eq!(client.send("MOVE taken 1").await, b":0\r\n");
+        assert_eq!(client.send("GET taken").await, b"$1\r\n0\r\n");
+
+        assert_eq!(
+            client.send("MOVE taken 0").await,
+            b"-ERR source and destination objects are the same\r\n"
+        );
+        assert_eq!(
+            client.send("MOVE taken 16").await,
+            b"-ERR DB index is out of range\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn incrbyfloat_formats_like_redis() {
+        let mut client = Client::connect(start_server(None).await).await;
+        assert_eq!(client.send("SET float 10.5").await, b"+OK\r\n");
+        assert_eq!(
+            client.send("INCRBYFLOAT float 0.1").await,
+            b"$4\r\n10.6\r\n"
+        );
+        // Whole numbers have no trailing `.0`, and exponents are expanded.
+        assert_eq!(client.send("INCRBYFLOAT float -0.6").await, b"$2\r\n10\r\n");
+        assert_eq!(
+            client.send("INCRBYFLOAT float 5.0e3").await,
+            b"$4\r\n5010\r\n"
+        );
+        assert_eq!(client.send("GET float").await, b"$4\r\n5010\r\n");
+        assert_eq!(client.send("INCRBY float 1").await, b":5011\r\n");
+        assert_eq!(client.send("INCRBYFLOAT missing 3").await, b"$1\r\n3\r\n");
+
+        for command in [
+            "INCRBYFLOAT float nan",
+            "INCRBYFLOAT float inf",
+            "INCRBYFLOAT float 1e400",
+        ] {
+            assert_eq!(
+                client.send(command).await,
+                b"-ERR value is not a valid float\r\n"
+            );
+        }
+        assert_eq!(client.send("SET string abc").await, b"+OK\r\n");
+        assert_eq!(
+            client.send("INCRBYFLOAT string 1").await,
+            b"-ERR value is not a valid float\r\n"
+        );
+        assert_eq!(client.send("GET float").await, b"$4\r\n5011\r\n");
     }
 }
@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+
+use tokio::sync::broadcast;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
+
+use crate::glob::glob_match;
+use crate::resp::RespValue;
+
+/// Messages a channel can buffer for a slow subscriber before it starts
+/// missing some.
+const CHANNEL_CAPACITY: usize = 1024;
+
+/// A message published to a channel.
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub channel: String,
+    pub payload: Vec<u8>,
+}
+
+/// The channels and patterns with subscribers, shared by every connection.
+pub struct PubSub {
+    channels: HashMap<String, broadcast::Sender<Message>>,
+    patterns: HashMap<String, broadcast::Sender<Message>>,
+}
+
+impl PubSub {
+    pub fn new() -> Self {
+        PubSub {
+            channels: HashMap::new(),
+            patterns: HashMap::new(),
+        }
+    }
+
+    fn subscribe(&mut self, channel: &str) -> broadcast::Receiver<Message> {
+        self.channels
+            .entry(channel.to_string())
+            .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+            .subscribe()
+    }
+
+    fn psubscribe(&mut self, pattern: &str) -> broadcast::Receiver<Message> {
+        self.patterns
+            .entry(pattern.to_string())
+            .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+            .subscribe()
+    }
+
+    /// Sends `payload` to every subscriber of `channel` and of the patterns
+    /// matching it, returning how many received it.
+    pub fn publish(&mut self, channel: &str, payload: Vec<u8>) -> usize {
+        let message = Message {
+            channel: channel.to_string(),
+            payload,
+        };
+
+        let mut received = 0;
+        if let Some(sender) = self.channels.get(channel) {
+            match sender.send(message.clone()) {
+                Ok(count) => received += count,
+                Err(_) => {
+                    self.channels.remove(channel);
+                }
+            }
+        }
+        self.patterns.retain(|pattern, sender| {
+            if !glob_match(pattern.as_bytes(), channel.as_bytes()) {
+                return sender.receiver_count() > 0;
+            }
+            match sender.send(message.clone()) {
+                Ok(count) => {
+                    received += count;
+                    true
+                }
+                Err(_) => false,
+            }
+        });
+        received
+    }
+}
+
+/// The subscriptions of a single connection. Every subscribed channel and
+/// pattern has a task forwarding its messages into one queue, which the
+/// connection drains alongside the commands it reads.
+pub struct Subscriber {
+    sender: UnboundedSender<RespValue>,
+    channels: HashMap<String, JoinHandle<()>>,
+    patterns: HashMap<String, JoinHandle<()>>,
+}
+
+impl Subscriber {
+    pub fn new() -> (Self, UnboundedReceiver<RespValue>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let subscriber = Subscriber {
+            sender,
+            channels: HashMap::new(),
+            patterns: HashMap::new(),
+        };
+        (subscriber, receiver)
+    }
+
+    /// Number of active channel and pattern subscriptions.
+    pub fn count(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+
+    pub fn channels(&self) -> Vec<String> {
+        self.channels.keys().cloned().collect()
+    }
+
+    pub fn patterns(&self) -> Vec<String> {
+        self.patterns.keys().cloned().collect()
+    }
+
+    pub fn subscribe(&mut self, pubsub: &mut PubSub, channel: &str) {
+        if !self.channels.contains_key(channel) {
+            let forward = self.forward(pubsub.subscribe(channel), None);
+            self.channels.insert(channel.to_string(), forward);
+        }
+    }
+
+    pub fn unsubscribe(&mut self, channel: &str) {
+        if let Some(forward) = self.channels.remove(channel) {
+            forward.abort();
+        }
+    }
+
+    pub fn psubscribe(&mut self, pubsub: &mut PubSub, pattern: &str) {
+        if !self.patterns.contains_key(pattern) {
+            let forward = self.forward(pubsub.psubscribe(pattern), Some(pattern.to_string()));
+            self.patterns.insert(pattern.to_string(), forward);
+        }
+    }
+
+    pub fn punsubscribe(&mut self, pattern: &str) {
+        if let Some(forward) = self.patterns.remove(pattern) {
+            forward.abort();
+        }
+    }
+
+    /// Drops every channel and pattern subscription, without confirming them.
+    pub fn unsubscribe_all(&mut self) {
+        for (_, forward) in self.channels.drain().chain(self.patterns.drain()) {
+            forward.abort();
+        }
+    }
+
+    /// Spawns the task turning messages from `receiver` into `message` pushes,
+    /// or `pmessage` pushes for a pattern subscription.
+    fn forward(
+        &self,
+        mut receiver: broadcast::Receiver<Message>,
+        pattern: Option<String>,
+    ) -> JoinHandle<()> {
+        let sender = self.sender.clone();
+        tokio::spawn(async move {
+            loop {
+                let message = match receiver.recv().await {
+                    Ok(message) => message,
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                let mut push = match &pattern {
+                    Some(pattern) => vec![
+                        RespValue::BulkString(Some(b"pmessage".to_vec())),
+                        RespValue::BulkString(Some(pattern.clone().into_bytes())),
+                    ],
+                    None => vec![RespValue::BulkString(Some(b"message".to_vec()))],
+                };
+                push.push(RespValue::BulkString(Some(message.channel.into_bytes())));
+                push.push(RespValue::BulkString(Some(message.payload)));
+                if sender.send(RespValue::Push(push)).is_err() {
+                    break;
+                }
+            }
+        })
+    }
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        for forward in self.channels.values().chain(self.patterns.values()) {
+            forward.abort();
+        }
+    }
+}
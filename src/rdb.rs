@@ -0,0 +1,553 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::anyhow;
+use tokio::time::Instant;
+
+use crate::storage::{Db, Storage, Value};
+use crate::zset::SortedSet;
+
+/// Progress of RDB snapshots, reported in the INFO persistence section.
+pub struct SaveStatus {
+    pub bgsave_in_progress: AtomicBool,
+    /// Unix time in seconds of the last successful save.
+    pub last_save_time: AtomicU64,
+}
+
+impl SaveStatus {
+    pub fn new() -> Self {
+        SaveStatus {
+            bgsave_in_progress: AtomicBool::new(false),
+            last_save_time: AtomicU64::new(unix_millis() / 1000),
+        }
+    }
+}
+
+/// A key/value pair read from an RDB file, with the database it belongs to
+/// and its absolute expiry in unix milliseconds if it has one.
+struct Entry {
+    db: usize,
+    key: String,
+    value: Value,
+    expires_at: Option<u64>,
+}
+
+/// Loads the RDB file at `path` into `databases`, skipping keys that have
+/// already expired.
+pub fn load(path: &Path, databases: &mut [Db]) -> Result<(), anyhow::Error> {
+    let bytes = std::fs::read(path)?;
+    let now = unix_millis();
+
+    for entry in parse(&bytes)? {
+        let count = databases.len();
+        let db = databases.get_mut(entry.db).ok_or_else(|| {
+            anyhow!(
+                "RDB file uses database {} but only {} are configured",
+                entry.db,
+                count
+            )
+        })?;
+        let deadline = match entry.expires_at {
+            Some(expires_at) if expires_at <= now => continue,
+            Some(expires_at) => Instant::now().checked_add(Duration::from_millis(expires_at - now)),
+            None => None,
+        };
+        db.get_mut(&entry.key).set(entry.key, entry.value, deadline);
+    }
+
+    Ok(())
+}
+
+/// Writes every live key of `databases`, each given as its shards, to an RDB
+/// file at `path`. The file is written next to `path` first and then renamed
+/// over it, so readers never observe a partially written file. Fails without
+/// touching `path` if a stream would have to be saved.
+pub fn save(path: &Path, databases: &[Vec<&Storage>]) -> Result<(), anyhow::Error> {
+    // SAVE and BGSAVE may run at the same time, each with a file of its own.
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+
+    let bytes = serialize(databases)?;
+    let tmp_path = path.with_extension(format!(
+        "tmp-{}-{}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::SeqCst)
+    ));
+    std::fs::write(&tmp_path, bytes)?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+fn serialize(databases: &[Vec<&Storage>]) -> Result<Vec<u8>, anyhow::Error> {
+    let now = unix_millis();
+
+    let mut out = b"REDIS0011".to_vec();
+    out.push(0xFA);
+    write_string(&mut out, b"redis-ver");
+    write_string(&mut out, b"7.2.0");
+
+    for (db, shards) in databases.iter().enumerate() {
+        let entries = shards
+            .iter()
+            .flat_map(|shard| shard.data.iter())
+            .filter(|(_, data)| !data.is_expired())
+            .map(|(key, data)| (key, &data.value, data.ttl()))
+            .collect::<Vec<_>>();
+        // Streams are stored as listpacks, which are not implemented. Leaving
+        // them out would lose them on the next restart.
+        if let Some((key, ..)) = entries
+            .iter()
+            .find(|(_, value, _)| matches!(value, Value::Stream(_)))
+        {
+            return Err(anyhow!(
+                "key '{}' is a stream, which cannot be saved to RDB files",
+                key
+            ));
+        }
+        if entries.is_empty() {
+            continue;
+        }
+        let expires = entries.iter().filter(|(_, _, ttl)| ttl.is_some()).count();
+
+        out.push(0xFE);
+        write_length(&mut out, db);
+        out.push(0xFB);
+        write_length(&mut out, entries.len());
+        write_length(&mut out, expires);
+
+        for (key, value, ttl) in entries {
+            write_entry(&mut out, key, value, ttl, now);
+        }
+    }
+
+    out.push(0xFF);
+    let checksum = crc64(&out);
+    out.extend_from_slice(&checksum.to_le_bytes());
+    Ok(out)
+}
+
+/// Writes a key with its value, preceded by its expiry if it has one.
+fn write_entry(out: &mut Vec<u8>, key: &str, value: &Value, ttl: Option<Duration>, now: u64) {
+    if let Some(ttl) = ttl {
+        out.push(0xFC);
+        out.extend_from_slice(&(now + ttl.as_millis() as u64).to_le_bytes());
+    }
+    out.push(value_type(value));
+    write_string(out, key.as_bytes());
+    write_value(out, value);
+}
+
+fn value_type(value: &Value) -> u8 {
+    match value {
+        Value::String(_) => 0x00,
+        Value::List(_) => 0x01,
+        Value::Set(_) => 0x02,
+        Value::Hash(_) => 0x04,
+        Value::SortedSet(_) => 0x05,
+        Value::Stream(_) => unreachable!("streams are rejected by serialize"),
+    }
+}
+
+fn write_value(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::String(value) => write_string(out, value),
+        Value::List(items) => {
+            write_length(out, items.len());
+            for item in items {
+                write_string(out, item);
+            }
+        }
+        Value::Set(members) => {
+            write_length(out, members.len());
+            for member in members {
+                write_string(out, member);
+            }
+        }
+        Value::SortedSet(zset) => {
+            write_length(out, zset.len());
+            for (member, score) in zset.iter() {
+                write_string(out, member);
+                out.extend_from_slice(&score.to_le_bytes());
+            }
+        }
+        Value::Hash(fields) => {
+            write_length(out, fields.len());
+            for (field, value) in fields {
+                write_string(out, field);
+                write_string(out, value);
+            }
+        }
+        // Never saved, see `serialize`.
+        Value::Stream(_) => {}
+    }
+}
+
+/// Number of bytes `value` takes up in an RDB file, as reported by DEBUG
+/// OBJECT.
+pub fn serialized_length(value: &Value) -> usize {
+    let mut out = vec![];
+    write_value(&mut out, value);
+    out.len()
+}
+
+fn write_length(out: &mut Vec<u8>, len: usize) {
+    if len < 1 << 6 {
+        out.push(len as u8);
+    } else if len < 1 << 14 {
+        out.push(0x40 | (len >> 8) as u8);
+        out.push(len as u8);
+    } else if len <= u32::MAX as usize {
+        out.push(0x80);
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+    } else {
+        out.push(0x81);
+        out.extend_from_slice(&(len as u64).to_be_bytes());
+    }
+}
+
+fn write_string(out: &mut Vec<u8>, s: &[u8]) {
+    write_length(out, s.len());
+    out.extend_from_slice(s);
+}
+
+/// CRC-64 with the Jones polynomial, as used by Redis for RDB checksums.
+fn crc64(bytes: &[u8]) -> u64 {
+    const POLY: u64 = 0x95AC_9329_AC4B_C9B5;
+
+    let mut table = [0u64; 256];
+    for (i, entry) in table.iter_mut().enumerate() {
+        let mut crc = i as u64;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            };
+        }
+        *entry = crc;
+    }
+
+    bytes.iter().fold(0, |crc, &b| {
+        table[((crc ^ b as u64) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+pub fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+fn parse(bytes: &[u8]) -> Result<Vec<Entry>, anyhow::Error> {
+    let mut reader = Reader { bytes, pos: 0 };
+
+    if reader.take(5)? != b"REDIS" {
+        return Err(anyhow!("not an RDB file"));
+    }
+    reader.take(4)?;
+
+    let mut entries = vec![];
+    let mut db = 0;
+    let mut expires_at = None;
+
+    loop {
+        match reader.byte()? {
+            // Auxiliary field
+            0xFA => {
+                reader.string()?;
+                reader.string()?;
+            }
+            // Database selector
+            0xFE => {
+                db = reader.length()?;
+            }
+            // Hash table sizes
+            0xFB => {
+                reader.length()?;
+                reader.length()?;
+            }
+            // Expiry in seconds
+            0xFD => {
+                let seconds = u32::from_le_bytes(reader.take(4)?.try_into()?);
+                expires_at = Some(seconds as u64 * 1000);
+            }
+            // Expiry in milliseconds
+            0xFC => {
+                expires_at = Some(u64::from_le_bytes(reader.take(8)?.try_into()?));
+            }
+            // End of file, followed by a checksum
+            0xFF => break,
+            // String value
+            0x00 => {
+                let key = String::from_utf8(reader.string()?)?;
+                let value = reader.string()?;
+                entries.push(Entry {
+                    db,
+                    key,
+                    value: Value::String(Arc::new(value)),
+                    expires_at: expires_at.take(),
+                });
+            }
+            // List value
+            0x01 => {
+                let key = String::from_utf8(reader.string()?)?;
+                let len = reader.length()?;
+                let items = (0..len)
+                    .map(|_| reader.string())
+                    .collect::<Result<VecDeque<_>, _>>()?;
+                entries.push(Entry {
+                    db,
+                    key,
+                    value: Value::List(items),
+                    expires_at: expires_at.take(),
+                });
+            }
+            // Set value
+            0x02 => {
+                let key = String::from_utf8(reader.string()?)?;
+                let len = reader.length()?;
+                let members = (0..len)
+                    .map(|_| reader.string())
+                    .collect::<Result<HashSet<_>, _>>()?;
+                entries.push(Entry {
+                    db,
+                    key,
+                    value: Value::Set(members),
+                    expires_at: expires_at.take(),
+                });
+            }
+            // Sorted set value, with binary scores
+            0x05 => {
+                let key = String::from_utf8(reader.string()?)?;
+                let len = reader.length()?;
+                let mut zset = SortedSet::new();
+                for _ in 0..len {
+                    let member = reader.string()?;
+                    let score = f64::from_le_bytes(reader.take(8)?.try_into()?);
+                    zset.insert(member, score);
+                }
+                entries.push(Entry {
+                    db,
+                    key,
+                    value: Value::SortedSet(zset),
+                    expires_at: expires_at.take(),
+                });
+            }
+            // Hash value
+            0x04 => {
+                let key = String::from_utf8(reader.string()?)?;
+                let len = reader.length()?;
+                let fields = (0..len)
+                    .map(|_| Ok((reader.string()?, reader.string()?)))
+                    .collect::<Result<HashMap<_, _>, anyhow::Error>>()?;
+                entries.push(Entry {
+                    db,
+                    key,
+                    value: Value::Hash(fields),
+                    expires_at: expires_at.take(),
+                });
+            }
+            value_type => return Err(anyhow!("unsupported value type {}", value_type)),
+        }
+    }
+
+    Ok(entries)
+}
+
+enum Length {
+    Length(usize),
+    /// A string stored in a special format, identified by the remaining six
+    /// bits of the length byte.
+    Encoded(u8),
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], anyhow::Error> {
+        // Lengths come from the file, so may be anything up to `u64::MAX`.
+        let bytes = self
+            .pos
+            .checked_add(len)
+            .and_then(|end| self.bytes.get(self.pos..end))
+            .ok_or_else(|| anyhow!("unexpected end of RDB file"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, anyhow::Error> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn length_or_encoding(&mut self) -> Result<Length, anyhow::Error> {
+        let first = self.byte()?;
+        match first >> 6 {
+            0b00 => Ok(Length::Length((first & 0x3F) as usize)),
+            0b01 => Ok(Length::Length(
+                (((first & 0x3F) as usize) << 8) | self.byte()? as usize,
+            )),
+            0b10 => match first {
+                0x80 => Ok(Length::Length(
+                    u32::from_be_bytes(self.take(4)?.try_into()?) as usize,
+                )),
+                0x81 => Ok(Length::Length(
+                    u64::from_be_bytes(self.take(8)?.try_into()?) as usize,
+                )),
+                _ => Err(anyhow!("invalid length encoding {:#x}", first)),
+            },
+            _ => Ok(Length::Encoded(first & 0x3F)),
+        }
+    }
+
+    fn length(&mut self) -> Result<usize, anyhow::Error> {
+        match self.length_or_encoding()? {
+            Length::Length(len) => Ok(len),
+            Length::Encoded(_) => Err(anyhow!("expected a length")),
+        }
+    }
+
+    fn string(&mut self) -> Result<Vec<u8>, anyhow::Error> {
+        match self.length_or_encoding()? {
+            Length::Length(len) => Ok(self.take(len)?.to_vec()),
+            Length::Encoded(0) => Ok((self.byte()? as i8).to_string().into_bytes()),
+            Length::Encoded(1) => {
+                let value = i16::from_le_bytes(self.take(2)?.try_into()?);
+                Ok(value.to_string().into_bytes())
+            }
+            Length::Encoded(2) => {
+                let value = i32::from_le_bytes(self.take(4)?.try_into()?);
+                Ok(value.to_string().into_bytes())
+            }
+            Length::Encoded(3) => {
+                let compressed_len = self.length()?;
+                let len = self.length()?;
+                lzf_decompress(self.take(compressed_len)?, len)
+            }
+            Length::Encoded(encoding) => Err(anyhow!("unknown string encoding {}", encoding)),
+        }
+    }
+}
+
+/// The most memory reserved up front for a decompressed string, whose length
+/// is read from the file and cannot be trusted until it is reached.
+const MAX_PREALLOCATION: usize = 1 << 20;
+
+fn lzf_decompress(input: &[u8], len: usize) -> Result<Vec<u8>, anyhow::Error> {
+    let mut output = Vec::with_capacity(len.min(MAX_PREALLOCATION));
+    let mut i = 0;
+
+    while i < input.len() {
+        let ctrl = input[i] as usize;
+        i += 1;
+
+        if ctrl < 32 {
+            // Literal run of ctrl + 1 bytes
+            let run = input
+                .get(i..i + ctrl + 1)
+                .ok_or_else(|| anyhow!("corrupt LZF string"))?;
+            output.extend_from_slice(run);
+            i += ctrl + 1;
+        } else {
+            // Back reference
+            let mut run = ctrl >> 5;
+            if run == 7 {
+                run += *input.get(i).ok_or_else(|| anyhow!("corrupt LZF string"))? as usize;
+                i += 1;
+            }
+            let low = *input.get(i).ok_or_else(|| anyhow!("corrupt LZF string"))? as usize;
+            i += 1;
+
+            let back = ((ctrl & 0x1F) << 8) + low + 1;
+            if back > output.len() {
+                return Err(anyhow!("corrupt LZF string"));
+            }
+            let start = output.len() - back;
+            for j in 0..run + 2 {
+                output.push(output[start + j]);
+            }
+        }
+        if output.len() > len {
+            return Err(anyhow!("corrupt LZF string"));
+        }
+    }
+
+    if output.len() != len {
+        return Err(anyhow!("corrupt LZF string"));
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An RDB file holding the given bytes in place of its keyspace.
+    fn rdb(body: &[u8]) -> Vec<u8> {
+        [&b"REDIS0011"[..], body, &[0xFF]].concat()
+    }
+
+    #[test]
+    fn concurrent_saves_write_separate_files() {
+        let dir = std::env::temp_dir().join(format!("rdb-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("dump.rdb");
+
+        let memory = Arc::new(crate::storage::Memory::new());
+        let mut storage = Storage::new(memory.clone());
+        storage.set(
+            "key".to_string(),
+            Value::String(Arc::new(b"value".to_vec())),
+            None,
+        );
+        let saves = (0..8)
+            .map(|_| {
+                let path = path.clone();
+                let storage = storage.clone();
+                std::thread::spawn(move || save(&path, &[vec![&storage]]))
+            })
+            .collect::<Vec<_>>();
+        for save in saves {
+            save.join().unwrap().unwrap();
+        }
+
+        let mut databases = vec![Db::new(&memory)];
+        load(&path, &mut databases).unwrap();
+        assert!(databases[0].get_mut("key").get_mut("key").is_some());
+        // No temporary file is left behind.
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn lengths_past_the_end_of_the_file_are_errors() {
+        // A string key claiming `u64::MAX` bytes.
+        let huge = [&[0x00, 0x81][..], &[0xFF; 8]].concat();
+        assert!(parse(&rdb(&huge)).is_err());
+        let long = [0x00, 0x80, 0x7F, 0xFF, 0xFF, 0xFF, b'k'];
+        assert!(parse(&rdb(&long)).is_err());
+    }
+
+    #[test]
+    fn lzf_strings_are_checked_against_their_length() {
+        // "aaaaaa": a literal `a`, then a back reference copying 5 bytes.
+        let compressed = [0x00, b'a', 0x60, 0x00];
+        assert_eq!(lzf_decompress(&compressed, 6).unwrap(), b"aaaaaa");
+        assert!(lzf_decompress(&compressed, 5).is_err());
+        assert!(lzf_decompress(&compressed, 7).is_err());
+        // A huge claimed length is not reserved up front.
+        assert!(lzf_decompress(&compressed, usize::MAX).is_err());
+
+        let string = [
+            &[0x00, 0x01, b'k', 0xC3, 0x04, 0x81][..],
+            &[0xFF; 8],
+            &compressed,
+        ]
+        .concat();
+        assert!(parse(&rdb(&string)).is_err());
+    }
+}
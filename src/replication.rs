@@ -0,0 +1,160 @@
+use std::sync::Arc;
+
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::sync::Notify;
+
+use crate::resp::RespValue;
+use crate::storage::select;
+
+pub const REPLICATION_ID: &str = "8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb";
+
+/// Commands that modify the keyspace and therefore need to be forwarded to
+/// replicas once they succeed on the master.
+pub fn is_write_command(command: &str) -> bool {
+    matches!(
+        command,
+        "set"
+            | "setnx"
+            | "setex"
+            | "psetex"
+            | "mset"
+            | "del"
+            | "unlink"
+            | "getdel"
+            | "getset"
+            | "setbit"
+            | "expire"
+            | "pexpire"
+            | "expireat"
+            | "pexpireat"
+            | "persist"
+            | "rename"
+            | "renamenx"
+            | "copy"
+            | "move"
+            | "swapdb"
+            | "incr"
+            | "decr"
+            | "incrby"
+            | "decrby"
+            | "incrbyfloat"
+            | "append"
+            | "setrange"
+            | "lpush"
+            | "rpush"
+            | "lpop"
+            | "rpop"
+            | "hset"
+            | "hdel"
+            | "sadd"
+            | "srem"
+            | "zadd"
+            | "xadd"
+            | "flushdb"
+            | "flushall"
+    )
+}
+
+struct Replica {
+    id: usize,
+    sender: UnboundedSender<Vec<u8>>,
+    /// Replication offset last reported by the replica via `REPLCONF ACK`.
+    acked: usize,
+}
+
+pub struct Replication {
+    replicas: Vec<Replica>,
+    next_id: usize,
+    /// Number of bytes of write commands propagated to replicas so far on a
+    /// master, or processed from the master on a replica.
+    pub offset: usize,
+    /// Offset right after the last `REPLCONF GETACK` was propagated.
+    getack_offset: usize,
+    /// Woken whenever a replica acknowledges an offset.
+    pub acks: Arc<Notify>,
+    /// Database the propagated commands apply to, once a SELECT was sent.
+    selected_db: Option<usize>,
+}
+
+impl Replication {
+    pub fn new() -> Self {
+        Replication {
+            replicas: vec![],
+            next_id: 0,
+            offset: 0,
+            getack_offset: 0,
+            acks: Arc::new(Notify::new()),
+            selected_db: None,
+        }
+    }
+
+    /// Registers a replica that has completed PSYNC, returning its id and the
+    /// stream of serialized commands it should receive.
+    pub fn register(&mut self) -> (usize, UnboundedReceiver<Vec<u8>>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let id = self.next_id;
+        self.next_id += 1;
+        self.replicas.push(Replica {
+            id,
+            sender,
+            acked: 0,
+        });
+        // The new replica starts out in database 0.
+        self.selected_db = None;
+        (id, receiver)
+    }
+
+    /// Forwards a write that ran against database `db`, preceded by a SELECT
+    /// if the previous one ran against another database.
+    pub fn propagate(&mut self, db: usize, command: &RespValue) {
+        if self.selected_db != Some(db) {
+            self.send(&select(db));
+            self.selected_db = Some(db);
+        }
+        self.send(command);
+    }
+
+    fn send(&mut self, command: &RespValue) {
+        let bytes = command.to_bytes();
+        self.offset += bytes.len();
+        self.replicas
+            .retain(|replica| replica.sender.send(bytes.clone()).is_ok());
+    }
+
+    /// Like `request_ack`, but skipped when nothing was propagated since the
+    /// previous request.
+    pub fn request_pending_ack(&mut self) {
+        if self.offset != self.getack_offset {
+            self.request_ack();
+        }
+    }
+
+    /// Asks every replica to report its offset with `REPLCONF GETACK *`.
+    pub fn request_ack(&mut self) {
+        if self.replicas.is_empty() {
+            return;
+        }
+
+        self.send(&RespValue::Array(vec![
+            RespValue::BulkString(Some(b"REPLCONF".to_vec())),
+            RespValue::BulkString(Some(b"GETACK".to_vec())),
+            RespValue::BulkString(Some(b"*".to_vec())),
+        ]));
+        self.getack_offset = self.offset;
+    }
+
+    pub fn acknowledge(&mut self, id: usize, offset: usize) {
+        if let Some(replica) = self.replicas.iter_mut().find(|replica| replica.id == id) {
+            replica.acked = offset;
+        }
+        self.acks.notify_waiters();
+    }
+
+    /// Number of replicas that acknowledged at least `offset`.
+    pub fn acked_count(&self, offset: usize) -> usize {
+        self.replicas
+            .iter()
+            .filter(|replica| !replica.sender.is_closed() && replica.acked >= offset)
+            .count()
+    }
+}
@@ -1,5 +1,8 @@
+use std::fmt::{self, Write};
+use std::sync::Arc;
+
 use anyhow::Result;
-use bytes::BytesMut;
+use bytes::{Buf, BytesMut};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::TcpStream,
@@ -11,36 +14,171 @@ pub enum RespValue {
     Error(String),
     Integer(i64),
     BulkString(Option<Vec<u8>>),
+    /// A bulk string sharing its payload with a stored value, so replying
+    /// with it does not clone the value.
+    SharedBulkString(Arc<Vec<u8>>),
     Array(Vec<RespValue>),
+    Map(Vec<(RespValue, RespValue)>),
+    Set(Vec<RespValue>),
+    Double(f64),
+    Boolean(bool),
+    BigNumber(String),
+    /// Text along with a three-letter hint of its format, such as `txt` or
+    /// `mkd`, for clients to display it as is.
+    Verbatim {
+        format: [u8; 3],
+        data: Vec<u8>,
+    },
+    Null,
+    /// The RESP2 null array, which clients tell apart from the null bulk
+    /// string, e.g. as the reply of an aborted EXEC.
+    NullArray,
+    /// Out-of-band data such as pub/sub messages.
+    Push(Vec<RespValue>),
 }
 
 impl RespValue {
+    /// Plain text, as returned by INFO and CLIENT LIST.
+    pub fn text(text: String) -> RespValue {
+        RespValue::Verbatim {
+            format: *b"txt",
+            data: text.into_bytes(),
+        }
+    }
+
     pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        self.encode_into(&mut buf);
+        buf.into()
+    }
+
+    /// Serializes the value at the end of `buf`, without allocating for
+    /// nested values.
+    pub fn encode_into(&self, buf: &mut BytesMut) {
         match self {
-            RespValue::SimpleString(s) => format!("+{}\r\n", s).into_bytes(),
-            RespValue::Error(s) => format!("-{}\r\n", s).into_bytes(),
-            RespValue::Integer(i) => format!(":{}\r\n", i).into_bytes(),
-            RespValue::BulkString(Some(s)) => {
-                let mut resp = format!("${}\r\n", s.len()).into_bytes();
-                resp.extend_from_slice(s);
-                resp.extend_from_slice(b"\r\n");
-                resp
+            RespValue::SimpleString(s) => line(buf, b'+', s),
+            RespValue::Error(s) => line(buf, b'-', s),
+            RespValue::Integer(i) => line(buf, b':', i),
+            RespValue::BulkString(Some(s)) => blob(buf, b'$', s),
+            RespValue::BulkString(None) => buf.extend_from_slice(b"$-1\r\n"),
+            RespValue::SharedBulkString(s) => blob(buf, b'$', s),
+            RespValue::Array(a) => aggregate(buf, b'*', a),
+            RespValue::Map(m) => {
+                line(buf, b'%', m.len());
+                for (k, v) in m {
+                    k.encode_into(buf);
+                    v.encode_into(buf);
+                }
+            }
+            RespValue::Set(a) => aggregate(buf, b'~', a),
+            RespValue::Double(d) => line(buf, b',', format_double(*d)),
+            RespValue::Boolean(b) => line(buf, b'#', if *b { 't' } else { 'f' }),
+            RespValue::BigNumber(n) => line(buf, b'(', n),
+            RespValue::Verbatim { format, data } => {
+                line(buf, b'=', data.len() + 4);
+                buf.extend_from_slice(format);
+                buf.extend_from_slice(b":");
+                buf.extend_from_slice(data);
+                buf.extend_from_slice(b"\r\n");
             }
-            RespValue::BulkString(None) => "$-1\r\n".bytes().collect(),
+            RespValue::Null => buf.extend_from_slice(b"_\r\n"),
+            RespValue::NullArray => buf.extend_from_slice(b"*-1\r\n"),
+            RespValue::Push(a) => aggregate(buf, b'>', a),
+        }
+    }
+
+    /// Downgrades RESP3-only types to their closest RESP2 equivalent, for
+    /// connections that have not negotiated protocol 3 with HELLO.
+    pub fn into_resp2(self) -> RespValue {
+        match self {
             RespValue::Array(a) => {
-                let mut resp = format!("*{}\r\n", a.len()).into_bytes();
-                for v in a {
-                    resp.extend_from_slice(&v.to_bytes());
-                }
-                resp
+                RespValue::Array(a.into_iter().map(RespValue::into_resp2).collect())
             }
+            RespValue::Map(m) => RespValue::Array(
+                m.into_iter()
+                    .flat_map(|(k, v)| [k.into_resp2(), v.into_resp2()])
+                    .collect(),
+            ),
+            RespValue::Set(a) | RespValue::Push(a) => {
+                RespValue::Array(a.into_iter().map(RespValue::into_resp2).collect())
+            }
+            RespValue::Double(d) => RespValue::BulkString(Some(format_double(d).into_bytes())),
+            RespValue::Boolean(b) => RespValue::Integer(b as i64),
+            RespValue::BigNumber(n) => RespValue::BulkString(Some(n.into_bytes())),
+            RespValue::Verbatim { data, .. } => RespValue::BulkString(Some(data)),
+            RespValue::Null => RespValue::BulkString(None),
+            v => v,
         }
     }
+
+    /// Replaces nil bulk strings and arrays with the RESP3 null, for
+    /// connections that negotiated protocol 3 with HELLO.
+    pub fn into_resp3(self) -> RespValue {
+        match self {
+            RespValue::BulkString(None) | RespValue::NullArray => RespValue::Null,
+            RespValue::Array(a) => {
+                RespValue::Array(a.into_iter().map(RespValue::into_resp3).collect())
+            }
+            RespValue::Map(m) => RespValue::Map(
+                m.into_iter()
+                    .map(|(k, v)| (k.into_resp3(), v.into_resp3()))
+                    .collect(),
+            ),
+            RespValue::Set(a) => RespValue::Set(a.into_iter().map(RespValue::into_resp3).collect()),
+            RespValue::Push(a) => {
+                RespValue::Push(a.into_iter().map(RespValue::into_resp3).collect())
+            }
+            v => v,
+        }
+    }
+}
+
+/// Writes a `tag` byte followed by `value` and a CRLF.
+fn line(buf: &mut BytesMut, tag: u8, value: impl fmt::Display) {
+    buf.extend_from_slice(&[tag]);
+    write!(buf, "{}\r\n", value).expect("writing to a BytesMut cannot fail");
+}
+
+/// Writes a length-prefixed string such as a bulk string.
+fn blob(buf: &mut BytesMut, tag: u8, data: &[u8]) {
+    line(buf, tag, data.len());
+    buf.extend_from_slice(data);
+    buf.extend_from_slice(b"\r\n");
 }
 
+/// Writes a count-prefixed aggregate such as an array.
+fn aggregate(buf: &mut BytesMut, tag: u8, elements: &[RespValue]) {
+    line(buf, tag, elements.len());
+    for element in elements {
+        element.encode_into(buf);
+    }
+}
+
+fn format_double(d: f64) -> String {
+    if d.is_nan() {
+        "nan".to_string()
+    } else if d.is_infinite() {
+        if d > 0.0 { "inf" } else { "-inf" }.to_string()
+    } else {
+        d.to_string()
+    }
+}
+
+/// Replies buffered past this size are flushed without waiting for the
+/// connection to run out of requests.
+const OUTPUT_LIMIT: usize = 64 * 1024;
+
+/// Limits on what a peer can make the parser buffer or recurse into, like
+/// Redis's `proto-max-bulk-len` and `client-query-buffer-limit`.
+const MAX_BULK_LEN: i64 = 512 * 1024 * 1024;
+const MAX_AGGREGATE_LEN: i64 = 1024 * 1024;
+const MAX_BUFFER_LEN: usize = 1024 * 1024 * 1024;
+const MAX_DEPTH: usize = 128;
+
 pub struct RespParser {
     stream: TcpStream,
     buffer: BytesMut,
+    output: BytesMut,
 }
 
 impl RespParser {
@@ -48,122 +186,375 @@ impl RespParser {
         RespParser {
             stream,
             buffer: BytesMut::with_capacity(1024),
+            output: BytesMut::with_capacity(1024),
         }
     }
 
+    /// Returns the next value, only reading from the socket once every value
+    /// already buffered (e.g. from a pipelined write) has been consumed.
+    /// Buffered replies are flushed before waiting for more input, so a batch
+    /// of pipelined commands is answered with a single write.
     pub async fn parse(&mut self) -> Result<RespValue> {
-        let bytes_read = self.stream.read_buf(&mut self.buffer).await?;
+        loop {
+            let blank = blank_lines(&self.buffer);
+            self.buffer.advance(blank);
+            match parse_single(&self.buffer) {
+                Ok((resp, len)) => {
+                    self.buffer.advance(len);
+                    return Ok(resp);
+                }
+                Err(e) if e.is::<Incomplete>() => {}
+                Err(e) => return Err(e),
+            }
+            if self.buffer.len() > MAX_BUFFER_LEN {
+                return Err(anyhow::anyhow!("query buffer limit exceeded"));
+            }
 
-        if bytes_read == 0 {
-            return Err(anyhow::anyhow!("connection closed"));
+            self.flush().await?;
+            let bytes_read = self.stream.read_buf(&mut self.buffer).await?;
+
+            if bytes_read == 0 {
+                return Err(Closed.into());
+            }
         }
+    }
+
+    /// Reads an RDB transfer, framed like a bulk string but without the
+    /// trailing CRLF, as sent by a master after a full resynchronization.
+    pub async fn parse_rdb(&mut self) -> Result<Vec<u8>> {
+        loop {
+            match self.buffer.first() {
+                Some(b'$') | None => {}
+                Some(_) => return Err(anyhow::anyhow!("expected RDB transfer")),
+            }
+
+            if let Some((line, len)) = read_until_crlf(self.buffer.get(1..).unwrap_or_default()) {
+                let rdb_len = String::from_utf8(line.to_vec())?.parse::<usize>()?;
+                let total_len = 1 + len + rdb_len;
+
+                if self.buffer.len() >= total_len {
+                    let rdb = self.buffer[1 + len..total_len].to_vec();
+                    self.buffer.advance(total_len);
+                    return Ok(rdb);
+                }
+            }
+
+            self.flush().await?;
+            let bytes_read = self.stream.read_buf(&mut self.buffer).await?;
 
-        if let Ok((resp, _)) = parse_single(self.buffer.split()) {
-            Ok(resp)
-        } else {
-            Err(anyhow::anyhow!("incomplete response"))
+            if bytes_read == 0 {
+                return Err(anyhow::anyhow!("connection closed"));
+            }
         }
     }
 
+    /// Queues `resp` to be sent on the next `flush`.
     pub async fn write(&mut self, resp: RespValue) -> Result<()> {
-        self.stream.write_all(&resp.to_bytes()).await?;
-        self.stream.flush().await?;
-        Ok(())
+        resp.encode_into(&mut self.output);
+        self.flush_if_full().await
     }
 
+    /// Queues raw bytes to be sent on the next `flush`.
     pub async fn write_all(&mut self, data: Vec<u8>) -> Result<()> {
-        self.stream.write_all(&data).await?;
+        self.output.extend_from_slice(&data);
+        self.flush_if_full().await
+    }
+
+    async fn flush_if_full(&mut self) -> Result<()> {
+        if self.output.len() >= OUTPUT_LIMIT {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Sends every queued reply. This can be cancelled, e.g. by `select!`,
+    /// without losing anything: `write_buf` only consumes what it wrote.
+    pub async fn flush(&mut self) -> Result<()> {
+        while !self.output.is_empty() {
+            if self.stream.write_buf(&mut self.output).await? == 0 {
+                return Err(std::io::Error::from(std::io::ErrorKind::WriteZero).into());
+            }
+        }
+        self.stream.flush().await?;
         Ok(())
     }
 }
 
-fn parse_single(buffer: BytesMut) -> Result<(RespValue, usize)> {
-    match buffer[0] {
+/// Parses every complete value in `buffer`, returning them along with the
+/// number of bytes they occupied. A value cut off at the end is left out.
+pub fn parse_all(buffer: &[u8]) -> Result<(Vec<RespValue>, usize)> {
+    let mut values = vec![];
+    let mut consumed = 0;
+    while consumed < buffer.len() {
+        match parse_single(&buffer[consumed..]) {
+            Ok((value, len)) => {
+                values.push(value);
+                consumed += len;
+            }
+            Err(e) if e.is::<Incomplete>() => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok((values, consumed))
+}
+
+/// The length of the blank lines at the start of `buffer`. Like Redis, the
+/// server skips them, e.g. when a telnet user presses enter, rather than
+/// reading them as empty inline commands.
+fn blank_lines(buffer: &[u8]) -> usize {
+    let mut len = 0;
+    loop {
+        let rest = &buffer[len..];
+        let spaces = rest
+            .iter()
+            .take_while(|&&b| b == b' ' || b == b'\t')
+            .count();
+        if !rest[spaces..].starts_with(b"\r\n") {
+            return len;
+        }
+        len += spaces + 2;
+    }
+}
+
+/// Returned by `RespParser::parse` when the peer closed the connection.
+#[derive(Debug, thiserror::Error)]
+#[error("connection closed")]
+pub struct Closed;
+
+/// Returned by the parsers when the buffer does not yet hold a complete value.
+#[derive(Debug, thiserror::Error)]
+#[error("incomplete response")]
+struct Incomplete;
+
+/// Parses one value from the start of `buffer`, returning it along with the
+/// number of bytes it occupied.
+fn parse_single(buffer: &[u8]) -> Result<(RespValue, usize)> {
+    parse_nested(buffer, 0)
+}
+
+/// Parses a value nested in `depth` aggregates. The type-specific parsers
+/// below receive the buffer past the type byte and report the bytes they
+/// consumed from there.
+fn parse_nested(buffer: &[u8], depth: usize) -> Result<(RespValue, usize)> {
+    let Some(&first) = buffer.first() else {
+        return Err(Incomplete.into());
+    };
+    // Each level of nesting recurses, so a deep enough value would overflow
+    // the stack.
+    if depth > MAX_DEPTH {
+        return Err(anyhow::anyhow!("aggregates nested too deeply"));
+    }
+
+    let (resp, len) = match first {
         b'+' => parse_simple_string(&buffer[1..]),
         b'-' => parse_error(&buffer[1..]),
         b':' => parse_integer(&buffer[1..]),
         b'$' => parse_bulk_string(&buffer[1..]),
-        b'*' => parse_array(&buffer[1..]),
-        _ => Err(anyhow::anyhow!("invalid response")),
+        b'*' => parse_array(&buffer[1..], depth),
+        b'%' => parse_map(&buffer[1..], depth),
+        b'~' => parse_set(&buffer[1..], depth),
+        b'>' => parse_push(&buffer[1..], depth),
+        b',' => parse_double(&buffer[1..]),
+        b'#' => parse_boolean(&buffer[1..]),
+        b'(' => parse_big_number(&buffer[1..]),
+        b'=' => parse_verbatim(&buffer[1..]),
+        b'_' => parse_null(&buffer[1..]),
+        _ => return parse_inline(buffer),
+    }?;
+
+    Ok((resp, len + 1))
+}
+
+/// Parses an inline command such as `PING\r\n`, as typed into a telnet
+/// session, into an array of bulk strings.
+fn parse_inline(buffer: &[u8]) -> Result<(RespValue, usize)> {
+    if let Some((line, len)) = read_until_crlf(buffer) {
+        let array = line[..]
+            .split(|b: &u8| b.is_ascii_whitespace())
+            .filter(|word| !word.is_empty())
+            .map(|word| RespValue::BulkString(Some(word.to_vec())))
+            .collect();
+        Ok((RespValue::Array(array), len))
+    } else {
+        Err(Incomplete.into())
     }
 }
 
 fn parse_simple_string(buffer: &[u8]) -> Result<(RespValue, usize)> {
-    println!("string: {:?}", buffer);
     if let Some((line, len)) = read_until_crlf(buffer) {
-        let s = String::from_utf8(line[1..len - 2].to_vec())?;
+        let s = String::from_utf8(line.to_vec())?;
         Ok((RespValue::SimpleString(s), len))
     } else {
-        Err(anyhow::anyhow!("incomplete response"))
+        Err(Incomplete.into())
     }
 }
 
 fn parse_error(buffer: &[u8]) -> Result<(RespValue, usize)> {
     if let Some((line, len)) = read_until_crlf(buffer) {
-        let s = String::from_utf8(line[1..len - 2].to_vec())?;
+        let s = String::from_utf8(line.to_vec())?;
         Ok((RespValue::Error(s), len))
     } else {
-        Err(anyhow::anyhow!("incomplete response"))
+        Err(Incomplete.into())
     }
 }
 
 fn parse_integer(buffer: &[u8]) -> Result<(RespValue, usize)> {
     if let Some((line, len)) = read_until_crlf(buffer) {
-        let s = String::from_utf8(line[1..len - 2].to_vec())?;
+        let s = String::from_utf8(line.to_vec())?;
         let i = s.parse::<i64>()?;
         Ok((RespValue::Integer(i), len))
     } else {
-        Err(anyhow::anyhow!("incomplete response"))
+        Err(Incomplete.into())
     }
 }
 
 fn parse_bulk_string(buffer: &[u8]) -> Result<(RespValue, usize)> {
     if let Some((line, len)) = read_until_crlf(buffer) {
-        let s = String::from_utf8(line[0..len - 2].to_vec())?;
+        let s = String::from_utf8(line.to_vec())?;
         let string_len = s.parse::<i64>()?;
 
         if string_len == -1 {
             return Ok((RespValue::BulkString(None), len));
         }
+        if !(0..=MAX_BULK_LEN).contains(&string_len) {
+            return Err(anyhow::anyhow!("invalid bulk string length"));
+        }
 
-        let total_len = len as usize + string_len as usize;
+        let total_len = len + string_len as usize;
 
-        if buffer.len() < total_len {
-            return Err(anyhow::anyhow!("incomplete response {:?}", buffer.len()));
+        if buffer.len() < total_len + 2 {
+            return Err(Incomplete.into());
+        }
+        if &buffer[total_len..total_len + 2] != b"\r\n" {
+            return Err(anyhow::anyhow!("bulk string is not terminated by CRLF"));
         }
 
         let bulk_string = buffer[len..total_len].to_vec();
 
-        Ok((RespValue::BulkString(Some(bulk_string)), total_len + 1))
+        Ok((RespValue::BulkString(Some(bulk_string)), total_len + 2))
     } else {
-        Err(anyhow::anyhow!("incomplete response"))
+        Err(Incomplete.into())
+    }
+}
+
+fn parse_array(buffer: &[u8], depth: usize) -> Result<(RespValue, usize)> {
+    match parse_elements(buffer, 1, depth)? {
+        (Some(array), len) => Ok((RespValue::Array(array), len)),
+        (None, len) => Ok((RespValue::NullArray, len)),
     }
 }
 
-fn parse_array(buffer: &[u8]) -> Result<(RespValue, usize)> {
+fn parse_map(buffer: &[u8], depth: usize) -> Result<(RespValue, usize)> {
+    let (Some(elements), len) = parse_elements(buffer, 2, depth)? else {
+        return Err(anyhow::anyhow!("invalid aggregate length"));
+    };
+    let mut elements = elements.into_iter();
+    let mut map = vec![];
+    while let (Some(k), Some(v)) = (elements.next(), elements.next()) {
+        map.push((k, v));
+    }
+    Ok((RespValue::Map(map), len))
+}
+
+fn parse_set(buffer: &[u8], depth: usize) -> Result<(RespValue, usize)> {
+    let (Some(set), len) = parse_elements(buffer, 1, depth)? else {
+        return Err(anyhow::anyhow!("invalid aggregate length"));
+    };
+    Ok((RespValue::Set(set), len))
+}
+
+fn parse_push(buffer: &[u8], depth: usize) -> Result<(RespValue, usize)> {
+    let (Some(push), len) = parse_elements(buffer, 1, depth)? else {
+        return Err(anyhow::anyhow!("invalid aggregate length"));
+    };
+    Ok((RespValue::Push(push), len))
+}
+
+/// Parses an aggregate header followed by `count * per_entry` elements, or
+/// `None` for a count of -1, which only arrays may have.
+fn parse_elements(
+    buffer: &[u8],
+    per_entry: usize,
+    depth: usize,
+) -> Result<(Option<Vec<RespValue>>, usize)> {
     if let Some((line, len)) = read_until_crlf(buffer) {
-        let s = String::from_utf8(line[0..len - 2].to_vec())?;
-        let array_len = s.parse::<i64>()?;
+        let s = String::from_utf8(line.to_vec())?;
+        let count = s.parse::<i64>()?;
 
-        if array_len == -1 {
-            return Ok((RespValue::Array(vec![]), len as usize));
+        if count == -1 {
+            return Ok((None, len));
         }
+        // Any other negative count would otherwise wrap around to a huge one,
+        // and wait forever for elements that never come.
+        if !(0..=MAX_AGGREGATE_LEN).contains(&count) {
+            return Err(anyhow::anyhow!("invalid aggregate length"));
+        }
+        let element_count = count as usize * per_entry;
 
         let mut total_len = len;
-        let mut array = vec![];
+        let mut elements = vec![];
         let mut buf = &buffer[len..];
 
-        for _ in 0..array_len {
-            let (resp, len) = parse_single(BytesMut::from(buf))?;
+        for _ in 0..element_count {
+            let (resp, len) = parse_nested(buf, depth + 1)?;
 
-            array.push(resp);
-            total_len += len + 2;
-            buf = &buf[len + 2..];
+            elements.push(resp);
+            total_len += len;
+            buf = &buf[len..];
         }
 
-        Ok((RespValue::Array(array), total_len))
+        Ok((Some(elements), total_len))
+    } else {
+        Err(Incomplete.into())
+    }
+}
+
+fn parse_double(buffer: &[u8]) -> Result<(RespValue, usize)> {
+    if let Some((line, len)) = read_until_crlf(buffer) {
+        let s = String::from_utf8(line.to_vec())?;
+        let d = s.parse::<f64>()?;
+        Ok((RespValue::Double(d), len))
     } else {
-        Err(anyhow::anyhow!("incomplete response"))
+        Err(Incomplete.into())
+    }
+}
+
+fn parse_boolean(buffer: &[u8]) -> Result<(RespValue, usize)> {
+    match read_until_crlf(buffer) {
+        Some((line, len)) if &line[..] == b"t" => Ok((RespValue::Boolean(true), len)),
+        Some((line, len)) if &line[..] == b"f" => Ok((RespValue::Boolean(false), len)),
+        Some(_) => Err(anyhow::anyhow!("invalid boolean")),
+        None => Err(Incomplete.into()),
+    }
+}
+
+fn parse_big_number(buffer: &[u8]) -> Result<(RespValue, usize)> {
+    if let Some((line, len)) = read_until_crlf(buffer) {
+        let s = String::from_utf8(line.to_vec())?;
+        Ok((RespValue::BigNumber(s), len))
+    } else {
+        Err(Incomplete.into())
+    }
+}
+
+/// Parses a verbatim string, framed like a bulk string whose payload starts
+/// with its format and a colon.
+fn parse_verbatim(buffer: &[u8]) -> Result<(RespValue, usize)> {
+    match parse_bulk_string(buffer)? {
+        (RespValue::BulkString(Some(payload)), len) if payload.get(3) == Some(&b':') => {
+            let format = [payload[0], payload[1], payload[2]];
+            let data = payload[4..].to_vec();
+            Ok((RespValue::Verbatim { format, data }, len))
+        }
+        _ => Err(anyhow::anyhow!("invalid verbatim string")),
+    }
+}
+
+fn parse_null(buffer: &[u8]) -> Result<(RespValue, usize)> {
+    if let Some((_, len)) = read_until_crlf(buffer) {
+        Ok((RespValue::Null, len))
+    } else {
+        Err(Incomplete.into())
     }
 }
 
@@ -176,3 +567,231 @@ fn read_until_crlf(buffer: &[u8]) -> Option<(BytesMut, usize)> {
         None
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A parser reading from the returned end of a local connection.
+    async fn connected() -> (TcpStream, RespParser) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, RespParser::new(server))
+    }
+
+    #[tokio::test]
+    async fn parse_waits_for_values_split_across_reads() {
+        let (mut client, mut parser) = connected().await;
+        let command = b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nvalue\r\n";
+        let writer = tokio::spawn(async move {
+            for byte in command {
+                client.write_all(&[*byte]).await.unwrap();
+                client.flush().await.unwrap();
+                tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+            }
+            client
+        });
+
+        let value = parser.parse().await.unwrap();
+        assert_eq!(value.to_bytes(), command);
+        let _client = writer.await.unwrap();
+    }
+
+    #[test]
+    fn empty_bulk_string_consumes_its_trailing_crlf() {
+        let (value, len) = parse_single(b"$0\r\n\r\n+OK\r\n").unwrap();
+        assert!(matches!(value, RespValue::BulkString(Some(ref s)) if s.is_empty()));
+        assert_eq!(len, 6);
+    }
+
+    #[test]
+    fn bulk_string_may_contain_crlf() {
+        let (value, len) = parse_single(b"$8\r\nfoo\r\nbar\r\n").unwrap();
+        assert!(matches!(value, RespValue::BulkString(Some(ref s)) if s == b"foo\r\nbar"));
+        assert_eq!(len, 14);
+
+        let (values, len) = parse_all(b"$2\r\n\r\n\r\n$0\r\n\r\n").unwrap();
+        assert_eq!(values.len(), 2);
+        assert_eq!(len, 14);
+    }
+
+    #[test]
+    fn bulk_string_without_its_trailing_crlf_is_incomplete() {
+        for partial in [&b"$3\r\nfoo"[..], b"$3\r\nfoo\r", b"$3\r\nfo"] {
+            assert!(parse_single(partial).unwrap_err().is::<Incomplete>());
+        }
+        assert!(!parse_single(b"$3\r\nfooba").unwrap_err().is::<Incomplete>());
+    }
+
+    #[test]
+    fn array_consumes_exactly_its_elements() {
+        let input = b"*2\r\n$3\r\nfoo\r\n$3\r\nbar\r\n";
+        let (value, len) = parse_single(&[&input[..], b"+OK\r\n"].concat()).unwrap();
+        assert_eq!(len, input.len());
+        assert_eq!(value.to_bytes(), input);
+
+        let mixed = b"*4\r\n:1\r\n+two\r\n$0\r\n\r\n$-1\r\n";
+        let (value, len) = parse_single(mixed).unwrap();
+        assert_eq!(len, mixed.len());
+        assert_eq!(value.to_bytes(), mixed);
+    }
+
+    #[test]
+    fn nested_arrays_consume_exactly_their_elements() {
+        let input = b"*1\r\n*1\r\n:1\r\n";
+        let (value, len) = parse_single(input).unwrap();
+        assert_eq!(len, input.len());
+        let RespValue::Array(outer) = value else {
+            panic!("expected an array");
+        };
+        let [RespValue::Array(inner)] = &outer[..] else {
+            panic!("expected a nested array");
+        };
+        assert!(matches!(inner[..], [RespValue::Integer(1)]));
+
+        let input = b"*2\r\n*1\r\n$1\r\na\r\n*0\r\n";
+        assert_eq!(parse_single(input).unwrap().1, input.len());
+    }
+
+    #[test]
+    fn oversized_or_deep_aggregates_are_rejected() {
+        let deep = "*1\r\n".repeat(MAX_DEPTH + 2);
+        assert!(!parse_single(deep.as_bytes())
+            .unwrap_err()
+            .is::<Incomplete>());
+        let long = format!("*{}\r\n", MAX_AGGREGATE_LEN + 1);
+        assert!(!parse_single(long.as_bytes())
+            .unwrap_err()
+            .is::<Incomplete>());
+        let bulk = format!("${}\r\n", MAX_BULK_LEN + 1);
+        assert!(!parse_single(bulk.as_bytes())
+            .unwrap_err()
+            .is::<Incomplete>());
+    }
+
+    #[test]
+    fn encoded_values_parse_back_to_themselves() {
+        let values = [
+            RespValue::SimpleString("OK".to_string()),
+            RespValue::Error("ERR nope".to_string()),
+            RespValue::Integer(-42),
+            RespValue::BulkString(Some(b"hello".to_vec())),
+            RespValue::BulkString(Some(vec![])),
+            RespValue::BulkString(None),
+            RespValue::NullArray,
+            RespValue::Array(vec![]),
+            RespValue::Array(vec![
+                RespValue::Integer(1),
+                RespValue::Array(vec![RespValue::BulkString(None)]),
+            ]),
+            RespValue::Map(vec![(
+                RespValue::SimpleString("key".to_string()),
+                RespValue::Double(1.5),
+            )]),
+            RespValue::Set(vec![RespValue::Boolean(true), RespValue::Boolean(false)]),
+            RespValue::Double(f64::NEG_INFINITY),
+            RespValue::BigNumber("12345678901234567890".to_string()),
+            RespValue::text("a\r\nb".to_string()),
+            RespValue::Null,
+            RespValue::Push(vec![RespValue::BulkString(Some(b"message".to_vec()))]),
+        ];
+        for value in values {
+            let encoded = value.to_bytes();
+            let (parsed, len) = parse_single(&encoded).unwrap();
+            assert_eq!(len, encoded.len(), "{:?}", value);
+            assert_eq!(parsed.to_bytes(), encoded, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn empty_and_nil_strings_encode_per_protocol() {
+        let empty = RespValue::BulkString(Some(vec![]));
+        assert_eq!(empty.clone().into_resp2().to_bytes(), b"$0\r\n\r\n");
+        assert_eq!(empty.into_resp3().to_bytes(), b"$0\r\n\r\n");
+
+        let nil = RespValue::BulkString(None);
+        assert_eq!(nil.clone().into_resp2().to_bytes(), b"$-1\r\n");
+        assert_eq!(nil.into_resp3().to_bytes(), b"_\r\n");
+
+        assert_eq!(RespValue::NullArray.into_resp2().to_bytes(), b"*-1\r\n");
+        assert_eq!(RespValue::NullArray.into_resp3().to_bytes(), b"_\r\n");
+        assert_eq!(RespValue::Null.into_resp2().to_bytes(), b"$-1\r\n");
+    }
+
+    #[test]
+    fn xrange_reply_parses_into_nested_arrays() {
+        let reply = b"*2\r\n\
+            *2\r\n$15\r\n1526985054069-0\r\n*4\r\n$11\r\ntemperature\r\n$2\r\n36\r\n$8\r\nhumidity\r\n$2\r\n95\r\n\
+            *2\r\n$15\r\n1526985054079-0\r\n*2\r\n$11\r\ntemperature\r\n$2\r\n37\r\n";
+        let (value, len) = parse_single(reply).unwrap();
+        assert_eq!(len, reply.len());
+
+        let RespValue::Array(entries) = value else {
+            panic!("expected an array of entries");
+        };
+        let ids = entries
+            .iter()
+            .map(|entry| match entry {
+                RespValue::Array(entry) => match &entry[..] {
+                    [RespValue::BulkString(Some(id)), RespValue::Array(fields)] => {
+                        (String::from_utf8(id.clone()).unwrap(), fields.len())
+                    }
+                    _ => panic!("expected an ID and its fields"),
+                },
+                _ => panic!("expected an entry"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ids,
+            [
+                ("1526985054069-0".to_string(), 4),
+                ("1526985054079-0".to_string(), 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn null_array_reply_parses() {
+        let (value, len) = parse_single(b"*-1\r\n:1\r\n").unwrap();
+        assert!(matches!(value, RespValue::NullArray));
+        assert_eq!(len, 5);
+        // Only arrays have a null form.
+        assert!(parse_single(b"%-1\r\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn handshake_replies_and_rdb_are_read_in_sequence() {
+        let (mut client, mut parser) = connected().await;
+        client
+            .write_all(b"+PONG\r\n+FULLRESYNC abc 0\r\n$5\r\nREDIS*1\r\n$4\r\nPING\r\n")
+            .await
+            .unwrap();
+
+        assert_eq!(parser.parse().await.unwrap().to_bytes(), b"+PONG\r\n");
+        assert_eq!(
+            parser.parse().await.unwrap().to_bytes(),
+            b"+FULLRESYNC abc 0\r\n"
+        );
+        assert_eq!(parser.parse_rdb().await.unwrap(), b"REDIS");
+        assert_eq!(
+            parser.parse().await.unwrap().to_bytes(),
+            b"*1\r\n$4\r\nPING\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn blank_lines_are_skipped() {
+        let (mut client, mut parser) = connected().await;
+        client.write_all(b"\r\n \t\r\nPING\r\n\r\n").await.unwrap();
+        assert_eq!(
+            parser.parse().await.unwrap().to_bytes(),
+            b"*1\r\n$4\r\nPING\r\n"
+        );
+
+        client.write_all(b"*0\r\n").await.unwrap();
+        assert_eq!(parser.parse().await.unwrap().to_bytes(), b"*0\r\n");
+    }
+}
@@ -0,0 +1,55 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Server-wide activity counters, reported by the Stats section of INFO.
+pub struct Stats {
+    pub connections_received: AtomicU64,
+    pub commands_processed: AtomicU64,
+    keyspace_hits: AtomicU64,
+    keyspace_misses: AtomicU64,
+    /// Commands processed during the last sampled second.
+    ops_per_sec: AtomicU64,
+    /// `commands_processed` as of the last sample.
+    sampled: AtomicU64,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Stats {
+            connections_received: AtomicU64::new(0),
+            commands_processed: AtomicU64::new(0),
+            keyspace_hits: AtomicU64::new(0),
+            keyspace_misses: AtomicU64::new(0),
+            ops_per_sec: AtomicU64::new(0),
+            sampled: AtomicU64::new(0),
+        }
+    }
+
+    /// Counts a key lookup as a hit or a miss.
+    pub fn lookup(&self, found: bool) {
+        let counter = match found {
+            true => &self.keyspace_hits,
+            false => &self.keyspace_misses,
+        };
+        counter.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Updates `instantaneous_ops_per_sec`. Called once a second.
+    pub fn sample(&self) {
+        let processed = self.commands_processed.load(Ordering::SeqCst);
+        let previous = self.sampled.swap(processed, Ordering::SeqCst);
+        self.ops_per_sec
+            .store(processed.saturating_sub(previous), Ordering::SeqCst);
+    }
+
+    /// The INFO lines for these counters.
+    pub fn info(&self) -> String {
+        format!(
+            "total_connections_received:{}\ntotal_commands_processed:{}\ninstantaneous_ops_per_sec:{}\nkeyspace_hits:{}\nkeyspace_misses:{}\n",
+            self.connections_received.load(Ordering::SeqCst),
+            self.commands_processed.load(Ordering::SeqCst),
+            self.ops_per_sec.load(Ordering::SeqCst),
+            self.keyspace_hits.load(Ordering::SeqCst),
+            self.keyspace_misses.load(Ordering::SeqCst),
+        )
+    }
+}
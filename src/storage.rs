@@ -1,48 +1,1070 @@
-use std::collections::HashMap;
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::collections::{btree_map, BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
+use tokio::sync::{Notify, RwLock, RwLockReadGuard, RwLockWriteGuard};
 use tokio::time::Instant;
 
+use crate::rdb::unix_millis;
 use crate::resp::RespValue;
+use crate::stream::{Stream, StreamId};
+use crate::zset::SortedSet;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataType {
+    String,
+    List,
+    Hash,
+    Set,
+    SortedSet,
+    Stream,
+}
+
+impl DataType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            DataType::String => "string",
+            DataType::List => "list",
+            DataType::Hash => "hash",
+            DataType::Set => "set",
+            DataType::SortedSet => "zset",
+            DataType::Stream => "stream",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Value {
+    /// Shared with the replies returning it, so reading a large string does
+    /// not copy it. Writers go through `Arc::make_mut`.
+    String(Arc<Vec<u8>>),
+    List(VecDeque<Vec<u8>>),
+    Hash(HashMap<Vec<u8>, Vec<u8>>),
+    Set(HashSet<Vec<u8>>),
+    SortedSet(SortedSet),
+    Stream(Stream),
+}
+
+/// Rough bookkeeping bytes of a key and of each element of a collection, on
+/// top of their contents, for the memory accounting.
+const ENTRY_OVERHEAD: usize = 64;
+const ELEMENT_OVERHEAD: usize = 16;
+
+/// Size limits under which Redis keeps values in their compact encodings.
+const EMBSTR_MAX_LEN: usize = 44;
+const LISTPACK_MAX_ENTRIES: usize = 128;
+const LISTPACK_MAX_VALUE: usize = 64;
+const INTSET_MAX_ENTRIES: usize = 512;
+
+/// Redis keeps a single shared object for each integer below this, which it
+/// reports with a refcount that never drops.
+const SHARED_INTEGERS: i64 = 10000;
+const SHARED_REFCOUNT: i64 = i32::MAX as i64;
+
+impl Value {
+    pub fn kind(&self) -> DataType {
+        match self {
+            Value::String(_) => DataType::String,
+            Value::List(_) => DataType::List,
+            Value::Hash(_) => DataType::Hash,
+            Value::Set(_) => DataType::Set,
+            Value::SortedSet(_) => DataType::SortedSet,
+            Value::Stream(_) => DataType::Stream,
+        }
+    }
+
+    /// Number of elements of a collection, or 1 for a string.
+    pub fn elements(&self) -> usize {
+        match self {
+            Value::String(_) => 1,
+            Value::List(list) => list.len(),
+            Value::Hash(hash) => hash.len(),
+            Value::Set(set) => set.len(),
+            Value::SortedSet(zset) => zset.len(),
+            Value::Stream(stream) => stream.len(),
+        }
+    }
+
+    /// Approximate bytes used by the value, counting the contents of every
+    /// element along with a fixed overhead for each.
+    pub fn footprint(&self) -> usize {
+        match self {
+            Value::String(value) => value.len(),
+            Value::List(list) => elements_footprint(list.iter().map(Vec::len)),
+            Value::Hash(hash) => {
+                elements_footprint(hash.iter().map(|(field, value)| field.len() + value.len()))
+            }
+            Value::Set(set) => elements_footprint(set.iter().map(Vec::len)),
+            // Members are stored twice, by name and ordered by score.
+            Value::SortedSet(zset) => {
+                elements_footprint(zset.iter().map(|(member, _)| 2 * member.len() + 8))
+            }
+            Value::Stream(stream) => elements_footprint(
+                stream
+                    .range(StreamId::default(), StreamId::MAX)
+                    .map(|(_, fields)| fields.iter().map(|(f, v)| f.len() + v.len()).sum()),
+            ),
+        }
+    }
+
+    /// The refcount Redis would report for this value through OBJECT
+    /// REFCOUNT. Values are never actually shared.
+    pub fn refcount(&self) -> i64 {
+        let Value::String(value) = self else {
+            return 1;
+        };
+        // Only the canonical form of a number, without a sign or leading
+        // zeros, is turned into a shared integer.
+        let shared = std::str::from_utf8(value).is_ok_and(|value| {
+            value
+                .parse::<i64>()
+                .is_ok_and(|n| (0..SHARED_INTEGERS).contains(&n) && n.to_string() == value)
+        });
+        match shared {
+            true => SHARED_REFCOUNT,
+            false => 1,
+        }
+    }
+
+    /// The encoding Redis would use for this value, as reported by OBJECT
+    /// ENCODING. Values are not actually stored differently: this is inferred
+    /// from their size.
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            Value::String(value) => match std::str::from_utf8(value).map(str::parse::<i64>) {
+                Ok(Ok(_)) => "int",
+                _ if value.len() <= EMBSTR_MAX_LEN => "embstr",
+                _ => "raw",
+            },
+            Value::List(list) => match is_compact(list.len(), list.iter().map(Vec::as_slice)) {
+                true => "listpack",
+                false => "quicklist",
+            },
+            Value::Hash(hash) => {
+                let entries = hash
+                    .iter()
+                    .flat_map(|(field, value)| [field.as_slice(), value.as_slice()]);
+                match is_compact(hash.len(), entries) {
+                    true => "listpack",
+                    false => "hashtable",
+                }
+            }
+            Value::Set(set) => {
+                let integers = set.iter().all(|member| {
+                    std::str::from_utf8(member).is_ok_and(|member| member.parse::<i64>().is_ok())
+                });
+                if integers && set.len() <= INTSET_MAX_ENTRIES {
+                    "intset"
+                } else if is_compact(set.len(), set.iter().map(Vec::as_slice)) {
+                    "listpack"
+                } else {
+                    "hashtable"
+                }
+            }
+            Value::SortedSet(zset) => {
+                match is_compact(zset.len(), zset.iter().map(|(member, _)| member)) {
+                    true => "listpack",
+                    false => "skiplist",
+                }
+            }
+            Value::Stream(_) => "stream",
+        }
+    }
+}
+
+/// A random number, good enough to pick keys but not for anything needing
+/// unpredictability. Every `RandomState` is seeded differently, so hashing
+/// nothing with a fresh one gives a new number each time.
+fn random() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+fn elements_footprint(sizes: impl Iterator<Item = usize>) -> usize {
+    sizes.map(|size| size + ELEMENT_OVERHEAD).sum()
+}
+
+/// Whether a collection is small enough for Redis to keep it as a listpack.
+fn is_compact<'a>(len: usize, mut entries: impl Iterator<Item = &'a [u8]>) -> bool {
+    len <= LISTPACK_MAX_ENTRIES && entries.all(|entry| entry.len() <= LISTPACK_MAX_VALUE)
+}
+
+#[derive(Clone)]
 pub struct Data {
-    pub value: RespValue,
-    pub created: Instant,
-    pub expiry: Option<usize>,
+    pub value: Value,
+    /// When the entry expires, if it has an expiry.
+    pub deadline: Option<Instant>,
+    pub accessed: AccessTime,
+    /// Approximate bytes used by the entry when it was last accounted for.
+    footprint: usize,
+    /// Position of the key in `Storage::slots`.
+    slot: usize,
+    /// Bumped whenever the entry is written, so WATCH can detect
+    /// modifications.
+    version: u64,
+}
+
+/// When an entry was last read or written, in milliseconds since the Unix
+/// epoch, for OBJECT IDLETIME. Atomic so that reads under a shared lock can
+/// update it too.
+pub struct AccessTime(AtomicU64);
+
+impl AccessTime {
+    fn now() -> Self {
+        AccessTime(AtomicU64::new(unix_millis()))
+    }
+
+    fn mark(&self) {
+        self.0.store(unix_millis(), Ordering::Relaxed);
+    }
+
+    /// Time since the entry was last accessed.
+    pub fn idle(&self) -> Duration {
+        Duration::from_millis(unix_millis().saturating_sub(self.0.load(Ordering::Relaxed)))
+    }
 }
 
+impl Clone for AccessTime {
+    fn clone(&self) -> Self {
+        AccessTime(AtomicU64::new(self.0.load(Ordering::Relaxed)))
+    }
+}
+
+impl Data {
+    pub fn is_expired(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| Instant::now() > deadline)
+    }
+
+    /// Remaining time to live, if the entry has an expiry.
+    pub fn ttl(&self) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+}
+
+#[derive(Clone)]
 pub struct Storage {
     pub data: HashMap<String, Data>,
+    /// Keys with an expiry, ordered by deadline for the active expiry sweep.
+    expires: BTreeSet<(Instant, String)>,
+    /// Keys removed because their time to live elapsed, for INFO stats.
+    pub expired_keys: u64,
+    /// Wakes clients blocked in BLPOP or XREAD when a key is written to.
+    /// Locked on its own so that a `Waiter` can deregister when dropped.
+    waiters: Waiters,
+    /// The version of every key that does not exist, bumped whenever one is
+    /// deleted or expires. Versions of existing and missing keys come from
+    /// the same counter, so they never collide.
+    vacant: u64,
+    next_version: u64,
+    /// Every key, in no particular order, so that keys can be sampled at
+    /// random for eviction.
+    slots: Vec<String>,
+    memory: Arc<Memory>,
+    /// Keys modified in place since their footprint was last accounted for,
+    /// while `memory` is tracking.
+    resized: HashSet<String>,
 }
 
 impl Storage {
-    pub fn new() -> Self {
+    pub fn new(memory: Arc<Memory>) -> Self {
         Storage {
             data: HashMap::new(),
+            expires: BTreeSet::new(),
+            expired_keys: 0,
+            waiters: Waiters::default(),
+            vacant: 0,
+            next_version: 1,
+            slots: vec![],
+            memory,
+            resized: HashSet::new(),
         }
     }
 
-    pub fn set(&mut self, key: String, value: RespValue, expiry: Option<usize>) {
-        let created = Instant::now();
-        self.data.insert(
-            key,
-            Data {
-                value,
-                created,
-                expiry,
-            },
-        );
+    pub fn set(&mut self, key: String, value: Value, deadline: Option<Instant>) {
+        let footprint = key.len() + value.footprint() + ENTRY_OVERHEAD;
+        self.memory.add(footprint);
+        let slot = match self.data.get(&key) {
+            Some(previous) => previous.slot,
+            None => {
+                self.slots.push(key.clone());
+                self.slots.len() - 1
+            }
+        };
+        let data = Data {
+            value,
+            deadline,
+            accessed: AccessTime::now(),
+            footprint,
+            slot,
+            version: self.next_version(),
+        };
+        if let Some(previous) = self.data.insert(key.clone(), data) {
+            self.unindex(&key, &previous);
+        }
+        // Only indexed once the previous entry is gone, which may have had
+        // the same deadline.
+        if let Some(deadline) = deadline {
+            self.expires.insert((deadline, key));
+        }
     }
 
-    pub fn get(&mut self, key: &str) -> Option<RespValue> {
+    /// Like `lookup`, but usable under a read lock: expired entries are skipped
+    /// instead of evicted.
+    pub fn peek(&self, key: &str) -> Option<&Value> {
+        let data = self.data.get(key).filter(|data| !data.is_expired())?;
+        data.accessed.mark();
+        Some(&data.value)
+    }
+
+    /// Whether `key` expired but was not evicted yet.
+    pub fn is_expired(&self, key: &str) -> bool {
+        self.data.get(key).is_some_and(|data| data.is_expired())
+    }
+
+    /// Borrows the value at `key`, which counts as an access to it.
+    pub fn lookup(&mut self, key: &str) -> Option<&Value> {
+        self.remove_if_expired(key);
+
         let data = self.data.get(key)?;
+        data.accessed.mark();
+        Some(&data.value)
+    }
+
+    pub fn kind(&mut self, key: &str) -> Option<DataType> {
+        self.remove_if_expired(key);
+
+        self.data.get(key).map(|data| data.value.kind())
+    }
 
-        if let Some(expiry) = data.expiry {
-            if data.created.elapsed().as_millis() > expiry as u128 {
-                self.data.remove(key);
-                return None;
+    pub fn keys(&self) -> impl Iterator<Item = &String> {
+        self.data
+            .iter()
+            .filter(|(_, data)| !data.is_expired())
+            .map(|(key, _)| key)
+    }
+
+    /// Number of keys that have not expired, whether or not they were evicted
+    /// yet.
+    pub fn len_live(&self) -> usize {
+        self.data.values().filter(|data| !data.is_expired()).count()
+    }
+
+    /// Number of keys with an expiry that have not expired yet.
+    pub fn expires_live(&self) -> usize {
+        let now = Instant::now();
+        self.expires
+            .iter()
+            .filter(|(deadline, _)| *deadline >= now)
+            .count()
+    }
+
+    /// Borrows an entry for modification, which counts as a write to `key`.
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut Data> {
+        self.remove_if_expired(key);
+
+        if let Some(data) = self.data.get(key) {
+            data.accessed.mark();
+            self.touch(key);
+            // The caller is about to change the value, so its size is worked
+            // out on the next call instead. That also settles the keys
+            // changed by the previous ones.
+            if self.memory.is_tracking() {
+                self.settle();
+                self.resized.insert(key.to_string());
+            }
+        }
+        self.data.get_mut(key)
+    }
+
+    pub fn exists(&mut self, key: &str) -> bool {
+        self.remove_if_expired(key);
+
+        self.data.contains_key(key)
+    }
+
+    pub fn del(&mut self, key: &str) -> bool {
+        self.take(key).is_some()
+    }
+
+    pub fn take(&mut self, key: &str) -> Option<Value> {
+        self.remove(key).map(|data| data.value)
+    }
+
+    /// Like `take`, but keeps the expiry along with the value.
+    pub fn remove(&mut self, key: &str) -> Option<Data> {
+        self.remove_if_expired(key);
+
+        let data = self.data.remove(key)?;
+        self.unindex(key, &data);
+        self.unslot(data.slot);
+        self.touch(key);
+        Some(data)
+    }
+
+    /// Borrows an entry with its expiry, without counting as an access: for
+    /// copying it to another key or inspecting it with OBJECT.
+    pub fn entry(&mut self, key: &str) -> Option<&Data> {
+        self.remove_if_expired(key);
+
+        self.data.get(key)
+    }
+
+    /// Stores an entry moved or copied from another key, replacing whatever
+    /// `key` held. Clients blocked on `key` are woken, as it may now hold the
+    /// list or stream they wait for.
+    pub fn insert(&mut self, key: String, data: Data) {
+        self.notify_waiters(&key);
+        self.set(key, data.value, data.deadline);
+    }
+
+    pub fn expire(&mut self, key: &str, deadline: Instant) -> bool {
+        self.remove_if_expired(key);
+
+        let Some(data) = self.data.get_mut(key) else {
+            return false;
+        };
+        if let Some(previous) = data.deadline.replace(deadline) {
+            self.expires.remove(&(previous, key.to_string()));
+        }
+        self.expires.insert((deadline, key.to_string()));
+        self.touch(key);
+        true
+    }
+
+    /// Clears the expiry of `key`, returning whether it had one.
+    pub fn persist(&mut self, key: &str) -> bool {
+        self.remove_if_expired(key);
+
+        let Some(deadline) = self.data.get_mut(key).and_then(|data| data.deadline.take()) else {
+            return false;
+        };
+        self.expires.remove(&(deadline, key.to_string()));
+        self.touch(key);
+        true
+    }
+
+    /// Returns the remaining time to live, `Some(None)` for a key without an
+    /// expiry and `None` when the key does not exist.
+    pub fn ttl(&mut self, key: &str) -> Option<Option<Duration>> {
+        self.remove_if_expired(key);
+
+        self.data.get(key).map(|data| data.ttl())
+    }
+
+    /// Exchanges the contents of two databases. Clients blocked on or
+    /// watching a key of either database see it as modified.
+    pub fn swap(&mut self, other: &mut Storage) {
+        std::mem::swap(&mut self.data, &mut other.data);
+        std::mem::swap(&mut self.expires, &mut other.expires);
+        std::mem::swap(&mut self.slots, &mut other.slots);
+        std::mem::swap(&mut self.resized, &mut other.resized);
+
+        let keys = self
+            .data
+            .keys()
+            .chain(other.data.keys())
+            .cloned()
+            .collect::<Vec<_>>();
+        for key in keys {
+            for storage in [&mut *self, &mut *other] {
+                storage.touch(&key);
+                storage.notify_waiters(&key);
+            }
+        }
+    }
+
+    /// Removes every key.
+    pub fn flush(&mut self) {
+        self.vacant = self.next_version();
+        self.memory
+            .sub(self.data.values().map(|data| data.footprint).sum());
+        self.data.clear();
+        self.expires.clear();
+        self.slots.clear();
+        self.resized.clear();
+    }
+
+    /// Accounts for the new size of the keys modified in place since the last
+    /// call.
+    fn settle(&mut self) {
+        for key in std::mem::take(&mut self.resized) {
+            if let Some(data) = self.data.get_mut(&key) {
+                let footprint = key.len() + data.value.footprint() + ENTRY_OVERHEAD;
+                self.memory.sub(data.footprint);
+                self.memory.add(footprint);
+                data.footprint = footprint;
+            }
+        }
+    }
+
+    /// Accounts for the size of every key anew, once `memory` starts tracking
+    /// the keys modified in place.
+    fn resettle(&mut self) {
+        self.resized = self.data.keys().cloned().collect();
+        self.settle();
+    }
+
+    /// A key picked at random, with the time since it was last accessed.
+    fn pick(&self) -> Option<(String, Duration)> {
+        if self.slots.is_empty() {
+            return None;
+        }
+        let key = &self.slots[random() as usize % self.slots.len()];
+        Some((key.clone(), self.data[key].accessed.idle()))
+    }
+
+    /// Evicts expired keys in deadline order, looking at no more than `limit`
+    /// of them so the caller holds the lock only briefly. Returns how many
+    /// were looked at: fewer than `limit` means nothing is left to evict.
+    pub fn evict_expired(&mut self, limit: usize) -> usize {
+        let now = Instant::now();
+        let mut seen = 0;
+        while seen < limit {
+            match self.expires.first() {
+                Some((deadline, _)) if *deadline < now => {}
+                _ => break,
             }
+            let (_, key) = self.expires.pop_first().unwrap();
+            self.remove_if_expired(&key);
+            seen += 1;
         }
+        seen
+    }
 
-        Some(data.value.clone())
+    /// The current version of `key`, which changes on every modification.
+    pub fn version(&mut self, key: &str) -> u64 {
+        self.remove_if_expired(key);
+
+        match self.data.get(key) {
+            Some(data) => data.version,
+            None => self.vacant,
+        }
+    }
+
+    /// Gives `key` a new version, or every missing key if it does not exist.
+    fn touch(&mut self, key: &str) {
+        let version = self.next_version();
+        match self.data.get_mut(key) {
+            Some(data) => data.version = version,
+            None => self.vacant = version,
+        }
+    }
+
+    fn next_version(&mut self) -> u64 {
+        self.next_version += 1;
+        self.next_version
+    }
+
+    /// Registers a client to be woken the next time `key` is pushed or
+    /// appended to, until the returned `Waiter` is dropped.
+    pub fn waiter(&mut self, key: &str) -> Waiter {
+        let notify = self
+            .waiters
+            .lock()
+            .unwrap()
+            .entry(key.to_string())
+            .or_default()
+            .clone();
+        Waiter {
+            key: key.to_string(),
+            notify: Some(notify),
+            waiters: self.waiters.clone(),
+        }
+    }
+
+    /// Wakes every client blocked on `key`.
+    pub fn notify_waiters(&mut self, key: &str) {
+        if let Some(notify) = self.waiters.lock().unwrap().remove(key) {
+            notify.notify_waiters();
+        }
+    }
+
+    pub fn remove_if_expired(&mut self, key: &str) {
+        if self.data.get(key).is_some_and(|data| data.is_expired()) {
+            if let Some(data) = self.data.remove(key) {
+                self.unindex(key, &data);
+                self.unslot(data.slot);
+            }
+            self.touch(key);
+            self.expired_keys += 1;
+        }
+    }
+
+    fn unindex(&mut self, key: &str, data: &Data) {
+        self.memory.sub(data.footprint);
+        self.resized.remove(key);
+        if let Some(deadline) = data.deadline {
+            self.expires.remove(&(deadline, key.to_string()));
+        }
+    }
+
+    /// Frees the slot of a removed key, moving the last key into it.
+    fn unslot(&mut self, slot: usize) {
+        self.slots.swap_remove(slot);
+        if let Some(moved) = self.slots.get(slot) {
+            self.data.get_mut(moved).unwrap().slot = slot;
+        }
+    }
+}
+
+/// Approximate bytes used by the keys and values of every database, for
+/// maxmemory. Shared by all the shards so that it can be checked without
+/// locking any of them.
+pub struct Memory {
+    used: AtomicUsize,
+    /// Whether values modified in place are accounted for. Working out their
+    /// new size walks the whole value, so this is only done with a maxmemory
+    /// limit set; sets and deletes are always accounted for.
+    tracking: AtomicBool,
+}
+
+impl Memory {
+    pub fn new() -> Self {
+        Memory {
+            used: AtomicUsize::new(0),
+            tracking: AtomicBool::new(false),
+        }
+    }
+
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    pub fn is_tracking(&self) -> bool {
+        self.tracking.load(Ordering::Relaxed)
+    }
+
+    fn add(&self, bytes: usize) {
+        self.used.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn sub(&self, bytes: usize) {
+        self.used.fetch_sub(bytes, Ordering::Relaxed);
+    }
+}
+
+/// The SELECT command switching to database `db`, as written to replicas and
+/// the append-only file before commands that ran against it.
+pub fn select(db: usize) -> RespValue {
+    RespValue::Array(vec![
+        RespValue::BulkString(Some(b"SELECT".to_vec())),
+        RespValue::BulkString(Some(db.to_string().into_bytes())),
+    ])
+}
+
+/// The notifiers of the clients blocked on keys of a shard, by key.
+type Waiters = Arc<std::sync::Mutex<HashMap<String, Arc<Notify>>>>;
+
+/// A client blocked on a key. However its wait ends, dropping it forgets the
+/// key's notifier once no other client waits on the key.
+pub struct Waiter {
+    key: String,
+    /// Only taken by `drop`.
+    notify: Option<Arc<Notify>>,
+    waiters: Waiters,
+}
+
+impl Waiter {
+    pub fn notify(&self) -> &Notify {
+        self.notify.as_ref().expect("taken only by drop")
+    }
+}
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        let mut waiters = self.waiters.lock().unwrap();
+        let notify = self.notify.take().expect("taken only by drop");
+        // A wakeup already removed the notifier, and a client blocking again
+        // since may have registered a new one.
+        if waiters
+            .get(&self.key)
+            .is_some_and(|registered| Arc::ptr_eq(registered, &notify))
+        {
+            drop(notify);
+            if Arc::strong_count(&waiters[&self.key]) == 1 {
+                waiters.remove(&self.key);
+            }
+        }
+    }
+}
+
+/// Shards every database is split into. Each has its own lock, so commands
+/// on keys of different shards do not wait for each other.
+const SHARDS: usize = 16;
+
+/// A logical database, sharded by key. Every shard is a `Storage` of its own,
+/// with its own expiry index, waiters and versions.
+pub struct Db {
+    shards: Vec<RwLock<Storage>>,
+}
+
+impl Db {
+    pub fn new(memory: &Arc<Memory>) -> Self {
+        Db {
+            shards: (0..SHARDS)
+                .map(|_| RwLock::new(Storage::new(memory.clone())))
+                .collect(),
+        }
+    }
+
+    /// Index of the shard holding `key`.
+    fn shard(key: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        hasher.finish() as usize % SHARDS
+    }
+
+    pub async fn read(&self, key: &str) -> RwLockReadGuard<'_, Storage> {
+        self.shards[Db::shard(key)].read().await
+    }
+
+    pub async fn write(&self, key: &str) -> RwLockWriteGuard<'_, Storage> {
+        self.shards[Db::shard(key)].write().await
+    }
+
+    /// Locks the shards holding `keys` for writing. They are always locked in
+    /// index order, so clients locking overlapping shards cannot deadlock.
+    pub async fn write_keys<'a>(&self, keys: impl IntoIterator<Item = &'a str>) -> Shards<'_> {
+        let indices = keys.into_iter().map(Db::shard).collect::<BTreeSet<_>>();
+        let mut guards = BTreeMap::new();
+        for index in indices {
+            guards.insert(index, self.shards[index].write().await);
+        }
+        Shards { guards }
+    }
+
+    /// Locks the shards holding `keys` for reading, in index order like
+    /// `write_keys`.
+    pub async fn read_keys<'a>(&self, keys: impl IntoIterator<Item = &'a str>) -> ReadShards<'_> {
+        let indices = keys.into_iter().map(Db::shard).collect::<BTreeSet<_>>();
+        let mut guards = BTreeMap::new();
+        for index in indices {
+            guards.insert(index, self.shards[index].read().await);
+        }
+        ReadShards { guards }
+    }
+
+    /// Locks every shard for writing, in index order.
+    pub async fn write_all(&self) -> Shards<'_> {
+        let mut guards = BTreeMap::new();
+        for (index, shard) in self.shards.iter().enumerate() {
+            guards.insert(index, shard.write().await);
+        }
+        Shards { guards }
+    }
+
+    /// Locks every shard for reading, in index order.
+    pub async fn read_all(&self) -> Vec<RwLockReadGuard<'_, Storage>> {
+        let mut guards = Vec::with_capacity(SHARDS);
+        for shard in &self.shards {
+            guards.push(shard.read().await);
+        }
+        guards
+    }
+
+    /// The shard holding `key`, without locking since the database is not
+    /// shared yet.
+    pub fn get_mut(&mut self, key: &str) -> &mut Storage {
+        self.shards[Db::shard(key)].get_mut()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &RwLock<Storage>> {
+        self.shards.iter()
+    }
+
+    /// Up to `count` keys picked at random, each from a random shard that has
+    /// some, with the time since they were last accessed.
+    pub async fn sample(&self, count: usize) -> Vec<(String, Duration)> {
+        let mut sampled = vec![];
+        for _ in 0..count {
+            let start = random() as usize;
+            for offset in 0..SHARDS {
+                let shard = self.shards[(start + offset) % SHARDS].read().await;
+                if let Some(key) = shard.pick() {
+                    sampled.push(key);
+                    break;
+                }
+            }
+        }
+        sampled
+    }
+
+    /// A key picked uniformly at random among those that have not expired.
+    pub async fn random_key(&self) -> Option<String> {
+        let shards = self.read_all().await;
+        let keys = shards
+            .iter()
+            .flat_map(|shard| shard.keys())
+            .collect::<Vec<_>>();
+        match keys.len() {
+            0 => None,
+            len => Some(keys[random() as usize % len].clone()),
+        }
+    }
+}
+
+/// Read locks on some of the shards of a database.
+pub struct ReadShards<'a> {
+    guards: BTreeMap<usize, RwLockReadGuard<'a, Storage>>,
+}
+
+impl ReadShards<'_> {
+    /// The shard holding `key`, which must be among the locked ones.
+    pub fn get(&self, key: &str) -> &Storage {
+        self.guards
+            .get(&Db::shard(key))
+            .expect("the shard holding the key is locked")
+    }
+}
+
+/// Write locks on some of the shards of a database.
+pub struct Shards<'a> {
+    guards: BTreeMap<usize, RwLockWriteGuard<'a, Storage>>,
+}
+
+impl<'a> Shards<'a> {
+    /// The shard holding `key`, which must be among the locked ones.
+    pub fn get(&mut self, key: &str) -> &mut Storage {
+        self.guards
+            .get_mut(&Db::shard(key))
+            .expect("the shard holding the key is locked")
+    }
+
+    pub fn iter_mut(&mut self) -> btree_map::ValuesMut<'_, usize, RwLockWriteGuard<'a, Storage>> {
+        self.guards.values_mut()
+    }
+
+    /// Moves the entry at `from` to `to` along with its expiry, replacing
+    /// whatever `to` held. Both keys must be locked, and may live in
+    /// different shards. Returns false if `from` does not exist.
+    pub fn rename(&mut self, from: &str, to: &str) -> bool {
+        match self.get(from).remove(from) {
+            Some(data) => {
+                self.get(to).insert(to.to_string(), data);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Swaps the contents of two databases locked entirely, shard by shard:
+    /// a key always hashes to the same shard index in every database.
+    pub fn swap(&mut self, other: &mut Shards) {
+        for (a, b) in self.iter_mut().zip(other.iter_mut()) {
+            a.swap(b);
+        }
+    }
+}
+
+/// The logical databases a connection can SELECT.
+pub struct Databases {
+    databases: Vec<Arc<Db>>,
+    /// The accounting shared by the shards of every database.
+    memory: Arc<Memory>,
+}
+
+impl Databases {
+    pub fn new(databases: Vec<Db>, memory: Arc<Memory>) -> Self {
+        Databases {
+            databases: databases.into_iter().map(Arc::new).collect(),
+            memory,
+        }
+    }
+
+    pub fn memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    /// Starts or stops accounting for values modified in place. Once started,
+    /// every key is accounted for anew, as they may have changed in between.
+    pub async fn track_memory(&self, tracking: bool) {
+        if self.memory.tracking.swap(tracking, Ordering::Relaxed) == tracking {
+            return;
+        }
+        for db in &self.databases {
+            for shard in db.iter() {
+                let mut shard = shard.write().await;
+                match tracking {
+                    true => shard.resettle(),
+                    false => shard.resized.clear(),
+                }
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.databases.len()
+    }
+
+    /// The database at `index`, which must be less than `len`.
+    pub fn get(&self, index: usize) -> Arc<Db> {
+        self.databases[index].clone()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<Db>> {
+        self.databases.iter()
+    }
+
+    /// Locks every shard of every database for reading, in index order.
+    pub async fn read_all(&self) -> Vec<Vec<RwLockReadGuard<'_, Storage>>> {
+        let mut locked = Vec::with_capacity(self.databases.len());
+        for db in &self.databases {
+            locked.push(db.read_all().await);
+        }
+        locked
+    }
+
+    /// Locks two different databases entirely for writing. They are always
+    /// locked in index order, so two clients locking the same pair cannot
+    /// deadlock.
+    pub async fn write_pair(&self, a: usize, b: usize) -> (Shards<'_>, Shards<'_>) {
+        assert_ne!(a, b, "cannot lock a database twice");
+        if a < b {
+            let first = self.databases[a].write_all().await;
+            (first, self.databases[b].write_all().await)
+        } else {
+            let second = self.databases[b].write_all().await;
+            (self.databases[a].write_all().await, second)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(value: &str) -> Value {
+        Value::String(Arc::new(value.as_bytes().to_vec()))
+    }
+
+    #[test]
+    fn len_live_skips_keys_expired_but_not_evicted() {
+        let mut storage = Storage::new(Arc::new(Memory::new()));
+        let now = Instant::now();
+        storage.set("live".to_string(), string("1"), None);
+        let later = now + Duration::from_secs(60);
+        storage.set("expiring".to_string(), string("2"), Some(later));
+        let earlier = now - Duration::from_millis(1);
+        storage.set("expired".to_string(), string("3"), Some(earlier));
+
+        assert_eq!(storage.data.len(), 3);
+        assert_eq!(storage.len_live(), 2);
+        assert_eq!(storage.expires_live(), 1);
+        assert_eq!(storage.keys().count(), 2);
+    }
+
+    #[tokio::test]
+    async fn keys_of_other_shards_stay_writable() {
+        let db = Db::new(&Arc::new(Memory::new()));
+        let held = "held";
+        let other = (0..)
+            .map(|i| format!("key:{}", i))
+            .find(|key| Db::shard(key) != Db::shard(held))
+            .unwrap();
+
+        let _guard = db.write(held).await;
+        let timeout = Duration::from_millis(50);
+        assert!(tokio::time::timeout(timeout, db.write(&other))
+            .await
+            .is_ok());
+        assert!(tokio::time::timeout(timeout, db.read(held)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn read_keys_shares_shards_with_readers() {
+        let db = Db::new(&Arc::new(Memory::new()));
+        db.write("a").await.set("a".to_string(), string("1"), None);
+
+        let shards = db.read_keys(["a", "b"]).await;
+        let timeout = Duration::from_millis(50);
+        assert!(tokio::time::timeout(timeout, db.read("a")).await.is_ok());
+        assert!(tokio::time::timeout(timeout, db.write("a")).await.is_err());
+        assert!(shards.get("a").peek("a").is_some());
+        assert!(shards.get("b").peek("b").is_none());
+    }
+
+    #[tokio::test]
+    async fn waiters_are_forgotten_however_the_wait_ends() {
+        let mut storage = Storage::new(Arc::new(Memory::new()));
+
+        // Woken through one of several keys, like a BLPOP on two lists.
+        let waiters = [storage.waiter("a"), storage.waiter("b")];
+        let woken = waiters[0].notify().notified();
+        storage.notify_waiters("a");
+        woken.await;
+        drop(waiters);
+        assert!(storage.waiters.lock().unwrap().is_empty());
+
+        // Timed out, or the client went away, while another still waits.
+        let first = storage.waiter("a");
+        let second = storage.waiter("a");
+        drop(first);
+        assert!(storage.waiters.lock().unwrap().contains_key("a"));
+        drop(second);
+        assert!(storage.waiters.lock().unwrap().is_empty());
+
+        // Blocking again after a wakeup registers a new notifier, which the
+        // old registration must leave alone.
+        let old = storage.waiter("a");
+        storage.notify_waiters("a");
+        let new = storage.waiter("a");
+        drop(old);
+        assert!(storage.waiters.lock().unwrap().contains_key("a"));
+        drop(new);
+        assert!(storage.waiters.lock().unwrap().is_empty());
+    }
+
+    /// Clients doing a mixed read/write workload over many keys, spread over
+    /// `shards` the way `Db` spreads them. Returns the operations per second.
+    async fn mixed_workload(shards: Arc<Vec<RwLock<Storage>>>) -> f64 {
+        const CLIENTS: usize = 32;
+        const OPS: usize = 20_000;
+        const KEYS: usize = 10_000;
+
+        let start = std::time::Instant::now();
+        let clients = (0..CLIENTS)
+            .map(|client| {
+                let shards = shards.clone();
+                tokio::spawn(async move {
+                    for op in 0..OPS {
+                        let key = format!("key:{}", (client * OPS + op) * 7919 % KEYS);
+                        let shard = &shards[Db::shard(&key) % shards.len()];
+                        // One write for every four reads.
+                        match op % 5 {
+                            0 => shard.write().await.set(key, string("value"), None),
+                            _ => drop(shard.read().await.peek(&key).cloned()),
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for client in clients {
+            client.await.unwrap();
+        }
+        (CLIENTS * OPS) as f64 / start.elapsed().as_secs_f64()
+    }
+
+    /// Compares the throughput of one lock for every key with that of the
+    /// shards. Run with `cargo test --release -- --ignored --nocapture`.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    #[ignore]
+    async fn sharding_throughput() {
+        let memory = Arc::new(Memory::new());
+        let single = vec![RwLock::new(Storage::new(memory.clone()))];
+        let sharded = Db::new(&memory).shards;
+
+        let single = mixed_workload(Arc::new(single)).await;
+        let sharded = mixed_workload(Arc::new(sharded)).await;
+        eprintln!(
+            "single lock: {:.0} ops/s, {} shards: {:.0} ops/s ({:.1}x)",
+            single,
+            SHARDS,
+            sharded,
+            sharded / single
+        );
     }
 }
@@ -0,0 +1,185 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::Bound;
+
+const INVALID_ID: &str = "Invalid stream ID specified as stream command argument";
+const ID_TOO_SMALL: &str =
+    "The ID specified in XADD is equal or smaller than the target stream top item";
+const IDS_EXHAUSTED: &str =
+    "The stream has exhausted the last possible ID, unable to add more items";
+
+/// The field/value pairs of a stream entry, in insertion order.
+pub type Fields = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// A stream entry ID, ordered by milliseconds and then sequence number.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+impl StreamId {
+    pub const MAX: StreamId = StreamId {
+        ms: u64::MAX,
+        seq: u64::MAX,
+    };
+
+    /// Parses a range bound: `-` and `+` for the smallest and largest IDs, or
+    /// an ID whose sequence number defaults to `default_seq` when omitted.
+    pub fn parse_bound(bound: &str, default_seq: u64) -> Option<StreamId> {
+        match bound {
+            "-" => Some(StreamId::default()),
+            "+" => Some(StreamId::MAX),
+            _ => match bound.split_once('-') {
+                Some((ms, seq)) => Some(StreamId {
+                    ms: ms.parse().ok()?,
+                    seq: seq.parse().ok()?,
+                }),
+                None => Some(StreamId {
+                    ms: bound.parse().ok()?,
+                    seq: default_seq,
+                }),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Stream {
+    entries: BTreeMap<StreamId, Fields>,
+    last_id: StreamId,
+}
+
+impl Stream {
+    pub fn new() -> Self {
+        Stream::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn last_id(&self) -> StreamId {
+        self.last_id
+    }
+
+    /// Resolves the ID argument of XADD, which is either `*`, `<ms>-*` or an
+    /// explicit `<ms>-<seq>`, against the last entry of the stream.
+    pub fn next_id(&self, requested: &str, now: u64) -> Result<StreamId, &'static str> {
+        let last = self.last_id;
+        let id = match requested.split_once('-') {
+            _ if requested == "*" => {
+                let ms = now.max(last.ms);
+                match ms == last.ms && !self.entries.is_empty() {
+                    true => match last.seq.checked_add(1) {
+                        Some(seq) => StreamId { ms, seq },
+                        // Every sequence number of the millisecond is taken.
+                        None => StreamId {
+                            ms: ms.checked_add(1).ok_or(IDS_EXHAUSTED)?,
+                            seq: 0,
+                        },
+                    },
+                    false => StreamId { ms, seq: 0 },
+                }
+            }
+            Some((ms, "*")) => {
+                let ms = ms.parse().map_err(|_| INVALID_ID)?;
+                let seq = match ms {
+                    ms if ms == last.ms && !self.entries.is_empty() => {
+                        last.seq.checked_add(1).ok_or(ID_TOO_SMALL)?
+                    }
+                    0 => 1,
+                    _ => 0,
+                };
+                StreamId { ms, seq }
+            }
+            Some((ms, seq)) => StreamId {
+                ms: ms.parse().map_err(|_| INVALID_ID)?,
+                seq: seq.parse().map_err(|_| INVALID_ID)?,
+            },
+            None => StreamId {
+                ms: requested.parse().map_err(|_| INVALID_ID)?,
+                seq: 0,
+            },
+        };
+
+        if id == StreamId::default() {
+            return Err("The ID specified in XADD must be greater than 0-0");
+        }
+        if id <= last {
+            return Err(ID_TOO_SMALL);
+        }
+        Ok(id)
+    }
+
+    /// Entries with IDs between `start` and `end`, inclusive.
+    pub fn range(
+        &self,
+        start: StreamId,
+        end: StreamId,
+    ) -> impl Iterator<Item = (&StreamId, &Fields)> {
+        // `BTreeMap::range` panics on inverted bounds, so use an empty range.
+        match start <= end {
+            true => self.entries.range(start..=end),
+            false => self.entries.range(..StreamId::default()),
+        }
+    }
+
+    /// Entries with IDs strictly greater than `id`.
+    pub fn after(&self, id: StreamId) -> impl Iterator<Item = (&StreamId, &Fields)> {
+        self.entries.range((Bound::Excluded(id), Bound::Unbounded))
+    }
+
+    /// Appends an entry. `id` must come from `next_id`.
+    pub fn add(&mut self, id: StreamId, fields: Fields) {
+        self.entries.insert(id, fields);
+        self.last_id = id;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream_ending_at(id: &str) -> Stream {
+        let mut stream = Stream::new();
+        let id = stream.next_id(id, 0).unwrap();
+        stream.add(id, vec![(b"f".to_vec(), b"v".to_vec())]);
+        stream
+    }
+
+    #[test]
+    fn auto_sequence_moves_to_the_next_millisecond_when_exhausted() {
+        let stream = stream_ending_at("5-18446744073709551615");
+        assert_eq!(stream.next_id("*", 5), Ok(StreamId { ms: 6, seq: 0 }));
+        assert_eq!(stream.next_id("*", 3), Ok(StreamId { ms: 6, seq: 0 }));
+        assert_eq!(stream.next_id("5-*", 0), Err(ID_TOO_SMALL));
+        assert_eq!(stream.next_id("6-*", 0), Ok(StreamId { ms: 6, seq: 0 }));
+
+        let stream = stream_ending_at("18446744073709551615-18446744073709551615");
+        assert_eq!(stream.next_id("*", 0), Err(IDS_EXHAUSTED));
+    }
+
+    #[test]
+    fn auto_sequence_follows_the_top_item() {
+        let stream = stream_ending_at("5-3");
+        assert_eq!(stream.next_id("*", 5), Ok(StreamId { ms: 5, seq: 4 }));
+        assert_eq!(stream.next_id("*", 7), Ok(StreamId { ms: 7, seq: 0 }));
+        assert_eq!(stream.next_id("5-*", 0), Ok(StreamId { ms: 5, seq: 4 }));
+        assert_eq!(stream.next_id("5-3", 0), Err(ID_TOO_SMALL));
+        assert_eq!(
+            Stream::new().next_id("0-*", 0),
+            Ok(StreamId { ms: 0, seq: 1 })
+        );
+    }
+}
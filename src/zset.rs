@@ -0,0 +1,125 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
+
+/// A score wrapper ordering floats with `total_cmp`, so scores can key a
+/// `BTreeSet`. NaN scores are rejected before they get here.
+#[derive(Debug, Clone, Copy)]
+struct Score(f64);
+
+impl PartialEq for Score {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// Members ordered by score, with ties broken lexicographically by member.
+#[derive(Debug, Clone, Default)]
+pub struct SortedSet {
+    scores: HashMap<Vec<u8>, f64>,
+    ordered: BTreeSet<(Score, Vec<u8>)>,
+}
+
+impl SortedSet {
+    pub fn new() -> Self {
+        SortedSet::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    /// Sets the score of `member`, returning whether it was newly added.
+    pub fn insert(&mut self, member: Vec<u8>, score: f64) -> bool {
+        // `total_cmp` orders -0.0 before 0.0, but they are the same score.
+        let score = if score == 0.0 { 0.0 } else { score };
+        let previous = self.scores.insert(member.clone(), score);
+        if let Some(previous) = previous {
+            self.ordered.remove(&(Score(previous), member.clone()));
+        }
+        self.ordered.insert((Score(score), member));
+        previous.is_none()
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    /// Zero-based position of `member` in ascending score order.
+    pub fn rank(&self, member: &[u8]) -> Option<usize> {
+        let score = self.score(member)?;
+        Some(
+            self.ordered
+                .range(..(Score(score), member.to_vec()))
+                .count(),
+        )
+    }
+
+    /// Members with their scores in ascending order.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&[u8], f64)> {
+        self.ordered
+            .iter()
+            .map(|(score, member)| (member.as_slice(), score.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn members(zset: &SortedSet) -> Vec<(&[u8], f64)> {
+        zset.iter().collect()
+    }
+
+    #[test]
+    fn updating_a_score_moves_the_member() {
+        let mut zset = SortedSet::new();
+        assert!(zset.insert(b"a".to_vec(), 1.0));
+        assert!(zset.insert(b"b".to_vec(), 2.0));
+        assert!(!zset.insert(b"a".to_vec(), 3.0));
+
+        assert_eq!(zset.len(), 2);
+        assert_eq!(zset.score(b"a"), Some(3.0));
+        assert_eq!(members(&zset), [(&b"b"[..], 2.0), (&b"a"[..], 3.0)]);
+    }
+
+    #[test]
+    fn negative_zero_is_zero() {
+        let mut zset = SortedSet::new();
+        zset.insert(b"a".to_vec(), -0.0);
+        assert!(!zset.insert(b"a".to_vec(), 0.0));
+        assert_eq!(zset.iter().count(), 1);
+    }
+
+    #[test]
+    fn ties_rank_lexicographically() {
+        let mut zset = SortedSet::new();
+        for member in [&b"c"[..], b"a", b"b"] {
+            zset.insert(member.to_vec(), 1.0);
+        }
+        zset.insert(b"z".to_vec(), 0.5);
+
+        assert_eq!(zset.rank(b"z"), Some(0));
+        assert_eq!(zset.rank(b"a"), Some(1));
+        assert_eq!(zset.rank(b"b"), Some(2));
+        assert_eq!(zset.rank(b"c"), Some(3));
+        assert_eq!(zset.rank(b"missing"), None);
+        // Ranks agree with the order ZRANGE lists members in.
+        for (rank, (member, _)) in zset.iter().enumerate() {
+            assert_eq!(zset.rank(member), Some(rank));
+        }
+    }
+}